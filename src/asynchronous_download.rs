@@ -1,99 +1,148 @@
 use std::io::Write;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::collections::HashMap;
 use std::str;
 use std::sync::Arc;
 
+use futures::future::{abortable, AbortHandle, Aborted};
 use futures::stream::FuturesUnordered;
 use metrics_runtime::{Receiver, Sink};
 use regex::Regex;
 use rusoto_core::Region;
 use rusoto_credential::AwsCredentials;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::task::JoinError;
 use tokio_rustls::*;
 
 use crate::config::Config;
 use crate::copy_exact::copy_exact;
-use crate::download_block::download_block_work;
+use crate::download_block::{download_block_work, BlockSettings};
+use crate::metric_names::METRIC_OVERALL_BLOCK_ERRORS;
 use crate::s3_info::S3ObjectBlock;
 use crate::s3_ip_pool::S3IpPool;
 
+// the number of endpoints we try a block on before giving up on the transfer
+const BLOCK_ATTEMPTS: u32 = 3;
+
 /// Asynchronously transfer a file from S3 using multiple connections each
-/// independently fetching blocks or parts of the file.
+/// independently fetching blocks or parts of the file. A block that fails is retried
+/// on other endpoints, and if it fails on all of them we return its error - having
+/// first aborted the blocks still in progress.
 ///
 pub async fn download_s3_file(
     receiver: &Receiver,
     s3_ip_pool: &Arc<S3IpPool>,
     blocks: Vec<S3ObjectBlock>,
     config: &Config,
+    block_settings: &BlockSettings,
     credentials: &AwsCredentials,
     bucket_region: &Region,
-) {
-    let mut slot_sockets = vec![
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 443);
-        config.s3_connections as usize
-    ];
-
-    // We will do some error tracking against connections this way
-    // let mut slot_errors = vec![0u16; config.s3_connections as usize];
-
-    // from our pool of S3 ip addresses we create slots that will target each of them
-    // up to the number of concurrent connections that have been asked for
-    // (note: possibly using the same S3 IP address more than once - as it turns out this doesn't matter)
-    for slot in 0..config.s3_connections as usize {
-        let (tcp_addr, _tcp_count) = s3_ip_pool.use_least_used_ip();
-
-        slot_sockets[slot] = SocketAddr::new(IpAddr::from(tcp_addr), 443);
-    }
-
+) -> anyhow::Result<()> {
     let mut futs = FuturesUnordered::new();
 
+    // the means to abort the worker currently in each slot (should any block fail)
+    let mut in_flight: HashMap<usize, AbortHandle> = HashMap::new();
+
     // the current slot indicates which S3 connection slot we are making units of work for
     let mut current_slot: usize = 0;
 
     for b in blocks {
+        // every block is a new connection so we choose an S3 endpoint (according to the
+        // pool's policy) each time - which lets any endpoints discovered during the transfer
+        // take on work and any that have been retired drop out
+        // (note: possibly using the same S3 IP address more than once - as it turns out this doesn't matter)
+        // the first block in each slot may have a connection already opened for it
+        let prewarmed = block_settings
+            .prewarmed
+            .as_ref()
+            .and_then(|p| p.take(current_slot));
+
+        let s3_addr = match &prewarmed {
+            Some(connection) => connection.s3_socket_addr,
+            None => SocketAddr::new(
+                s3_ip_pool.choose_ip(current_slot).0,
+                block_settings.s3_port(),
+            ),
+        };
+
         // before creating our async closure we create local variables that are copies
         // of any params - such that our spawned tokio task can own them forever
         // TODO: what is the idiomatic rust way of doing this??
         let local_credentials = credentials.clone();
-        let local_s3_addr = slot_sockets[current_slot];
+        let local_s3_addr = s3_addr;
         let local_s3_bucket_region = bucket_region.clone();
         let local_s3_bucket_name = config.input_bucket_name.clone();
         let local_s3_bucket_key = config.input_bucket_key.clone();
-        let local_memory_only = config.memory_only;
-        let local_output_filename = config.output_write_filename.clone();
+        let local_block_settings = block_settings.clone();
+        let local_s3_ip_pool = s3_ip_pool.clone();
 
         // construct a sink for any metrics
         let mut block_sink = receiver.sink();
 
         // create the worker to work in this slot and spawn it on any tokio runtime thread
-        futs.push(tokio::spawn(async move {
+        let (worker, abort_handle) = abortable(async move {
             let slot = current_slot;
 
-            let actual_work_future = download_block_work(
-                slot,
-                &mut block_sink,
-                &local_credentials,
-                local_s3_addr,
-                &local_s3_bucket_region,
-                local_s3_bucket_name.as_str(),
-                local_s3_bucket_key.as_str(),
-                b.start,
-                b.length,
-                b.part_number,
-                local_memory_only,
-                local_output_filename,
-                b.start,
-            );
-
-            // TODO: handle errors here - and start to disable slots if too many errors
-            // rather than have out futures loop deal with errors we want to put
-            // some error handling here - currently none
-            actual_work_future.await.unwrap();
+            let mut s3_addr = local_s3_addr;
+            let mut connection = prewarmed;
+            let mut attempt = 1;
+
+            loop {
+                let actual_work_future = download_block_work(
+                    slot,
+                    &mut block_sink,
+                    &local_credentials,
+                    s3_addr,
+                    &local_s3_bucket_region,
+                    local_s3_bucket_name.as_str(),
+                    local_s3_bucket_key.as_str(),
+                    b.start,
+                    b.length,
+                    b.part_number,
+                    &local_block_settings,
+                    b.start,
+                    connection.take(),
+                );
+
+                // a failed block counts against its endpoint (so that an endpoint that keeps
+                // failing is retired) and is tried again on another endpoint
+                match actual_work_future.await {
+                    Ok(result) => {
+                        local_s3_ip_pool.record_block(&result);
+                        break;
+                    }
+                    Err(e) => {
+                        block_sink.increment_counter(METRIC_OVERALL_BLOCK_ERRORS, 1);
+                        local_s3_ip_pool.record_error(&s3_addr, &e);
+                        local_s3_ip_pool.retire_ips();
+
+                        if attempt == BLOCK_ATTEMPTS {
+                            return Err(e.context(format!(
+                                "Block at offset {} failed on {} attempts",
+                                b.start, BLOCK_ATTEMPTS
+                            )));
+                        }
+                    }
+                }
+
+                attempt += 1;
+
+                s3_addr = SocketAddr::new(
+                    local_s3_ip_pool
+                        .choose_ip_avoiding(slot, Some(&s3_addr.ip()))
+                        .0,
+                    local_block_settings.s3_port(),
+                );
+            }
 
             // we need to return the slot *we* were in order that the next
             // worker that is created takes over our slot
-            slot
-        }));
+            Ok::<usize, anyhow::Error>(slot)
+        });
+
+        in_flight.insert(current_slot, abort_handle);
+
+        futs.push(tokio::spawn(worker));
 
         // this is only of relevance in the opening N iterations of the loop -
         // after the future we 'wait' on will define the replacement current_slot
@@ -103,13 +152,178 @@ pub async fn download_s3_file(
             // we have hit the limit of concurrency we are aiming for
             // so we now await the finish of (any!) worker
             // the slot it returns is then open for us to use as the next worker slot
-            current_slot = futures::stream::StreamExt::next(&mut futs)
-                .await
-                .unwrap()
-                .unwrap();
+            let finished = futures::stream::StreamExt::next(&mut futs).await.unwrap();
+
+            current_slot = worker_outcome(finished, &in_flight)?;
         }
     }
 
     // drain for remaining work from the queue
-    while let Some(_) = futures::stream::StreamExt::next(&mut futs).await {}
+    while let Some(finished) = futures::stream::StreamExt::next(&mut futs).await {
+        worker_outcome(finished, &in_flight)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the slot a finished worker was in - or if it failed, aborts every worker
+/// still running (so none are left detached from the transfer) and returns the error.
+///
+fn worker_outcome(
+    finished: Result<Result<anyhow::Result<usize>, Aborted>, JoinError>,
+    in_flight: &HashMap<usize, AbortHandle>,
+) -> anyhow::Result<usize> {
+    // we only abort workers once we have stopped waiting on them
+    let outcome = finished
+        .map_err(anyhow::Error::from)
+        .and_then(|worker| worker.expect("worker aborted while still awaited"));
+
+    if outcome.is_err() {
+        for abort_handle in in_flight.values() {
+            abort_handle.abort();
+        }
+    }
+
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+    use futures::future::{abortable, pending};
+    use metrics_runtime::Receiver;
+    use rusoto_core::Region;
+    use rusoto_credential::AwsCredentials;
+    use rustls::ClientConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::asynchronous_download::{download_s3_file, worker_outcome};
+    use crate::config::Config;
+    use crate::download_block::BlockSettings;
+    use crate::s3_info::S3ObjectBlock;
+    use crate::s3_ip_pool::S3IpPool;
+    use crate::tcp_socket::SocketSettings;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Returns the port of a plain HTTP server on the given IP that answers every
+    /// request with a block of zeros - much as S3 would.
+    ///
+    async fn fake_s3(ip: &str) -> u16 {
+        let mut listener =
+            TcpListener::from_std(std::net::TcpListener::bind((ip, 0)).unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buf = [0u8; 1024];
+
+                    while !request.ends_with(b"\n\n") {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+
+                    let headers = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                        BLOCK_SIZE
+                    );
+
+                    socket.write_all(headers.as_bytes()).await.unwrap();
+                    socket.write_all(&[0u8; BLOCK_SIZE]).await.unwrap();
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn a_failed_worker_aborts_the_others() {
+        let (still_running, abort_handle) = abortable(pending::<()>());
+
+        let mut in_flight = HashMap::new();
+        in_flight.insert(1, abort_handle);
+
+        assert_eq!(worker_outcome(Ok(Ok(Ok(0))), &in_flight).unwrap(), 0);
+
+        assert!(worker_outcome(Ok(Ok(Err(anyhow!("connection reset")))), &in_flight).is_err());
+
+        assert!(still_running.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_endpoints_are_retired_while_blocks_are_retried_elsewhere() {
+        // nothing listens on 127.0.0.1 at the port of our fake S3 on 127.0.0.2
+        let port = fake_s3("127.0.0.2").await;
+
+        let config = Config {
+            input_bucket_name: String::from("bucket"),
+            input_bucket_key: String::from("key"),
+            memory_only: true,
+            no_tls: true,
+            s3_connections: 1,
+            tcp_info_interval: None,
+            ..Config::default()
+        };
+
+        let mut block_settings = BlockSettings::new(
+            &config,
+            None,
+            Arc::new(ClientConfig::new()),
+            None,
+            None,
+            SocketSettings::from_config(&config).unwrap(),
+        );
+        block_settings.port = port;
+
+        let pool = Arc::new(S3IpPool::new());
+        pool.add_ips(&[
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            "127.0.0.2".parse::<IpAddr>().unwrap(),
+        ]);
+
+        let blocks = (0..6)
+            .map(|i| S3ObjectBlock {
+                start: (i * BLOCK_SIZE) as u64,
+                length: BLOCK_SIZE as u64,
+                part_number: 0,
+            })
+            .collect();
+
+        download_s3_file(
+            &Receiver::builder().build().unwrap(),
+            &pool,
+            blocks,
+            &config,
+            &block_settings,
+            &AwsCredentials::new("AKIDEXAMPLE", "secret", None, None),
+            &Region::UsEast1,
+        )
+        .await
+        .unwrap();
+
+        let ips = pool.ips.lock().unwrap();
+
+        // the least used policy keeps trying the failing endpoint until it is retired
+        let failing = ips.get("127.0.0.1").unwrap();
+        assert_eq!(failing.errors, 3);
+        assert!(failing.retired);
+
+        let working = ips.get("127.0.0.2").unwrap();
+        assert_eq!(working.blocks, 6);
+        assert_eq!(working.bytes, 6 * BLOCK_SIZE as u64);
+        assert!(!working.retired);
+    }
 }
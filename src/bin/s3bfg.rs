@@ -1,20 +1,20 @@
-use std::sync::Arc;
+use std::io::{self, Write};
 use std::time::Duration;
-use std::time::Instant;
 
 use metrics_core::{Builder as MetricsBuilder, Drain, Observe};
-use rusoto_credential::StaticProvider;
+use metrics_runtime::Receiver;
 
-use s3bfg::asynchronous_download::download_s3_file;
 use s3bfg::config::Config;
-use s3bfg::empty_file::create_empty_target_file;
+use s3bfg::metric_exporters::MetricsExporters;
 use s3bfg::metric_observer_ui::UiBuilder;
-use s3bfg::s3_info::{find_s3_object, S3ObjectDetails};
-use s3bfg::s3_ip_pool::S3IpPool;
-use s3bfg::setup_aws_credentials::fetch_credentials;
-use s3bfg::setup_metrics::create_metrics;
+use s3bfg::report::{create_report, endpoints_table, write_report, ReportFormat};
+use s3bfg::s3_etag::EtagCheck;
+use s3bfg::s3_info::S3ObjectDetails;
+use s3bfg::s3_ip_dns::DnsProtocol;
 use s3bfg::setup_tokio::create_runtime;
+use s3bfg::transfer::{EndpointSource, Transfer, TransferError, TransferStats};
 use s3bfg::ui_console::progress_worker;
+use s3bfg::ui_timeseries::TimeseriesRecorder;
 
 /// The big gun of S3 file copying.
 ///
@@ -22,98 +22,86 @@ fn main() -> std::io::Result<()> {
     // parse cmd line
     let config = Config::new();
 
-    // we use a metrics engine to help drive optimisations and progress meters etc
-    // the intention of this particular receiver is to record metrics across
-    // the entire run of the transfer (and not merely of a small time window)
-    let (receiver, _metrics_level) = create_metrics(&config);
+    // a JSON report is the only thing we write to stdout (so that it can be parsed) - and
+    // everything meant for people goes to stderr instead
+    let mut out: Box<dyn Write> = if config.report_format == ReportFormat::Json {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
 
     // we use tokio runtime for various async activity
     let (mut rt, rt_msg) = create_runtime(&config);
 
-    // a single set of credentials which we are assuming will last throughout the whole copy
-    let (creds, creds_msg) = rt.block_on(fetch_credentials(&config));
+    let transfer = match Transfer::from_config(config.clone()) {
+        Ok(transfer) => transfer,
+        Err(e) => {
+            report_invalid_settings(&config, &e, &mut out)?;
+            std::process::exit(1);
+        }
+    };
 
-    let cred_provider = StaticProvider::new(
-        creds.aws_access_key_id().to_string(),
-        creds.aws_secret_access_key().to_string(),
-        creds.token().clone(),
-        None,
-    );
+    let located = match rt.block_on(transfer.locate()) {
+        Ok(located) => located,
+        Err(e) => {
+            report_failure(&transfer, None, &e, &mut out)?;
+            std::process::exit(1);
+        }
+    };
 
-    println!("{}", creds_msg);
+    writeln!(out, "{}", located.credentials_description)?;
 
-    // try to find details of the s3 bucket and file
-    let s3_object_details = rt
-        .block_on(find_s3_object(
-            &cred_provider,
-            &config.input_bucket_name,
-            &config.input_bucket_key,
-        ))
-        .unwrap();
+    let config = transfer.config();
+    let s3_object_details = &located.object;
 
     if config.memory_only {
-        println!(
+        writeln!(
+            out,
             "Copying s3://{}/{} ({}) to /dev/null (network benchmark only)",
             config.input_bucket_name,
             config.input_bucket_key,
             s3_object_details.region.name()
-        );
+        )?;
     } else {
-        println!(
+        writeln!(
+            out,
             "Copying s3://{}/{} ({}) to {} (local)",
             config.input_bucket_name,
             config.input_bucket_key,
             s3_object_details.region.name(),
             config.output_write_filename.as_ref().unwrap().display()
-        );
+        )?;
     }
 
-    println!("{:?}", s3_object_details);
+    writeln!(out, "{:?}", s3_object_details)?;
 
-    println!("Running on: {}", config.instance_type);
-    println!("DNS server chosen: {}", config.dns_server);
-    println!(
+    writeln!(out, "Running on: {}", config.instance_type)?;
+    if let Some(config_file) = &config.config_file {
+        writeln!(out, "Config file: {}", config_file.display())?;
+    }
+    if let Some(preset) = &config.preset {
+        writeln!(out, "Preset: {}", preset)?;
+    }
+    match config.dns_protocol {
+        DnsProtocol::Https => writeln!(out, "DNS over HTTPS chosen: {}", config.dns_https_url)?,
+        DnsProtocol::System => writeln!(out, "DNS servers chosen: from /etc/resolv.conf")?,
+        _ => writeln!(out, "DNS server chosen: {}", config.dns_server)?,
+    }
+    writeln!(
+        out,
         "Aiming for {} distinct concurrent connections to S3",
         config.s3_connections
-    );
-
-    if !config.memory_only {
-        create_empty_target_file(
-            &config.output_write_filename.clone().unwrap().as_ref(),
-            s3_object_details.size_in_bytes,
-        )?;
-    }
-
-    let blocks = s3_object_details.break_into_blocks(None);
-
-    let total_started = Instant::now();
-
-    let s3_ip_pool = Arc::new(S3IpPool::new());
-
-    {
-        let dns_started = Instant::now();
-
-        rt.block_on(s3_ip_pool.populate_ips(
-            &s3_object_details.region,
-            config.dns_server.as_str(),
-            config.dns_desired_ips,
-            config.dns_rounds,
-            config.dns_concurrent,
-            config.dns_round_delay,
-        ));
-
-        let ips_db = s3_ip_pool.ips.lock().unwrap();
-
-        println!(
-            "Discovered {} distinct S3 endpoints in {}s",
-            ips_db.len(),
-            Instant::now().duration_since(dns_started).as_secs_f32()
-        );
+    )?;
+    if config.no_tls {
+        writeln!(out, "********************************************************************")?;
+        writeln!(out, "WARNING: TLS is turned off - data from S3 is NOT encrypted in transit")?;
+        writeln!(out, "********************************************************************")?;
     }
 
-    // start a regular (non tokio runtime) thread which displays a progress meter off our metrics
+    // start a regular (non tokio runtime) thread which displays a progress meter off our
+    // metrics (the meter is drawn on stderr)
     {
-        let controller = receiver.controller();
+        let controller = transfer.controller();
         let file_size_bytes = s3_object_details.size_in_bytes;
 
         std::thread::spawn(move || {
@@ -121,39 +109,191 @@ fn main() -> std::io::Result<()> {
         });
     }
 
-    println!(
+    writeln!(
+        out,
         "Tokio runtime is set up to operate with {} config, utilising {} S3 connections",
         rt_msg, config.s3_connections
+    )?;
+
+    // metrics exporting is best effort so we don't abort the copy if it can't start
+    let exporters = match MetricsExporters::start(transfer.controller(), config) {
+        Ok(exporters) => exporters,
+        Err(e) => {
+            writeln!(out, "Unable to start exporting metrics - {}", e)?;
+            None
+        }
+    };
+
+    let timeseries = match &config.timeseries_file {
+        Some(path) => {
+            match TimeseriesRecorder::start(
+                transfer.controller(),
+                path,
+                config.s3_connections as usize,
+            ) {
+                Ok(timeseries) => Some(timeseries),
+                Err(e) => {
+                    writeln!(out, "Unable to create time series file {} - {}", path.display(), e)?;
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let outcome = rt.block_on(transfer.download(&located));
+
+    writeln!(out)?;
+
+    rt.shutdown_timeout(Duration::from_millis(100));
+
+    if let Some(timeseries) = timeseries {
+        if let Err(e) = timeseries.finish() {
+            writeln!(out, "Failed writing time series - {}", e)?;
+        }
+    }
+
+    // make sure our exported metrics reflect the very end of the run
+    if let Some(exporters) = exporters {
+        exporters.finish();
+    }
+
+    match outcome {
+        Ok(stats) => report_success(&transfer, s3_object_details, &stats, &mut out),
+        Err(e) => {
+            report_failure(&transfer, Some(s3_object_details), &e, &mut out)?;
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Tells the user (and any report file) all about the successful run.
+///
+fn report_success(
+    transfer: &Transfer,
+    s3_object_details: &S3ObjectDetails,
+    stats: &TransferStats,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let config = transfer.config();
+
+    let report = create_report(
+        config,
+        Some(s3_object_details),
+        Ok(stats),
+        &transfer.controller(),
     );
 
-    rt.block_on(download_s3_file(
-        &receiver,
-        &s3_ip_pool,
-        blocks,
-        &config,
-        &creds,
-        &s3_object_details.region,
-    ));
+    if let Some(report_file) = &config.report_file {
+        write_report(&report, report_file)?;
+    }
 
-    println!();
+    if config.report_format == ReportFormat::Json {
+        println!("{}", report);
 
-    rt.shutdown_timeout(Duration::from_millis(100));
+        return Ok(());
+    }
 
-    let mut observer = UiBuilder::new().build();
+    match stats.s3_endpoints_source {
+        EndpointSource::Dns => writeln!(
+            out,
+            "Discovered {} distinct S3 endpoints in {}s",
+            stats.s3_endpoints,
+            stats.dns_duration.as_secs_f32()
+        )?,
+        EndpointSource::Cache => writeln!(
+            out,
+            "Using {} cached S3 endpoints (refreshing the cache in the background)",
+            stats.s3_endpoints
+        )?,
+        EndpointSource::Fixed => writeln!(out, "Using {} given S3 endpoints", stats.s3_endpoints)?,
+    }
 
-    receiver.controller().observe(&mut observer);
+    writeln!(out, "{}", endpoints_table(stats))?;
 
-    println!("{}", observer.drain());
+    let mut observer = UiBuilder::new().build();
 
-    let total_duration = Instant::now().duration_since(total_started);
+    transfer.controller().observe(&mut observer);
 
-    println!(
+    writeln!(out, "{}", observer.drain())?;
+
+    writeln!(
+        out,
         "{}: rate MiB/sec = {} (copied {} bytes in {}s)",
         "Overall",
-        (s3_object_details.size_in_bytes as f32 / (1024.0 * 1024.0)) / total_duration.as_secs_f32(),
-        s3_object_details.size_in_bytes,
-        total_duration.as_secs_f32()
-    );
+        stats.rate_mibs_per_sec(),
+        stats.bytes,
+        stats.duration.as_secs_f32()
+    )?;
+
+    // a copy that didn't match its ETag fails the run so never gets here
+    match &stats.verification {
+        Some(EtagCheck::Matched(etag)) => writeln!(out, "Verified the copy has the ETag {}", etag)?,
+        Some(EtagCheck::Unverifiable(reason)) => {
+            writeln!(out, "Unable to verify the copy as {}", reason)?
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Tells the user (and any report file) why the run failed.
+///
+fn report_failure(
+    transfer: &Transfer,
+    s3_object_details: Option<&S3ObjectDetails>,
+    error: &TransferError,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let config = transfer.config();
+
+    let report = create_report(config, s3_object_details, Err(error), &transfer.controller());
+
+    finish_failure_report(config, &report, error, out)
+}
+
+/// Tells the user (and any report file) that the settings were not valid - before we
+/// have even started a transfer (and so have no metrics to report).
+///
+fn report_invalid_settings(
+    config: &Config,
+    error: &TransferError,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let receiver = Receiver::builder()
+        .build()
+        .expect("failed to create metrics receiver");
+
+    let report = create_report(config, None, Err(error), &receiver.controller());
+
+    finish_failure_report(config, &report, error, out)
+}
+
+/// Writes the report of a failed run to any report file, and then to stdout if JSON
+/// was asked for - otherwise tells the user why it failed.
+///
+fn finish_failure_report(
+    config: &Config,
+    report: &serde_json::Value,
+    error: &TransferError,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    if let Some(report_file) = &config.report_file {
+        if let Err(e) = write_report(report, report_file) {
+            writeln!(out, "Unable to write report to {} - {}", report_file.display(), e)?;
+        }
+    }
+
+    if config.report_format == ReportFormat::Json {
+        println!("{}", report);
+    } else {
+        if let Some(stats) = error.stats() {
+            writeln!(out, "{}", endpoints_table(stats))?;
+        }
+
+        writeln!(out, "{}", error)?;
+    }
 
     Ok(())
 }
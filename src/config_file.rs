@@ -0,0 +1,309 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde_yaml::{Mapping, Value};
+
+// the file we look for when the user hasn't explicitly given us a config file
+const DEFAULT_CONFIG_FILE: &str = ".config/s3bfg.yaml";
+
+// the keys within a config file that are not settings in their own right
+const PRESET_KEY: &str = "preset";
+const PRESETS_KEY: &str = "presets";
+
+// presets that we ship with - a config file can define its own presets (including
+// replacing any of these) in a `presets` section
+//
+// all keys are the same as the long form of the equivalent command line argument
+const BUILTIN_PRESETS: &str = r##"
+nvme-instance:
+  connections: 64
+  block-size: 64
+  network-buffer-size: 512
+  disk-buffer-size: 4096
+  dns-desired-ips: 32
+  dns-concurrent: 32
+small-instance:
+  connections: 8
+  block-size: 16
+  network-buffer-size: 128
+  disk-buffer-size: 256
+  dns-concurrent: 4
+  dns-rounds: 2
+cross-region:
+  connections: 32
+  block-size: 128
+  network-buffer-size: 1024
+  dns-rounds: 8
+  dns-round-delay: 250ms
+"##;
+
+/// Settings read from a YAML configuration file, with any named preset
+/// the file refers to expanded underneath the file's own settings.
+///
+pub struct ConfigFile {
+    // the location we loaded from (if any)
+    pub path: Option<PathBuf>,
+
+    // the name of the preset that was applied (if any)
+    pub preset: Option<String>,
+
+    settings: Mapping,
+}
+
+impl ConfigFile {
+    /// Returns a config file with no settings.
+    ///
+    pub fn empty() -> ConfigFile {
+        ConfigFile {
+            path: None,
+            preset: None,
+            settings: Mapping::new(),
+        }
+    }
+
+    /// Loads the config file from the given path, or if no path is given, from
+    /// `~/.config/s3bfg.yaml` should it exist. A preset name given here
+    /// takes precedence over any preset named in the file itself. Every setting
+    /// (in the file or any of its presets) must be one of the known settings and
+    /// a single value.
+    ///
+    pub fn load(
+        explicit_path: Option<&Path>,
+        preset: Option<&str>,
+        known: &[&str],
+    ) -> Result<ConfigFile> {
+        let mut config_file = ConfigFile::empty();
+
+        let path = match explicit_path {
+            Some(p) => Some(p.to_path_buf()),
+            None => default_config_path().filter(|p| p.is_file()),
+        };
+
+        let mut file_presets = Mapping::new();
+
+        if let Some(p) = path {
+            let content = std::fs::read_to_string(&p)
+                .with_context(|| format!("Unable to read config file {}", p.display()))?;
+
+            let (settings, presets) = parse_config(content.as_str())
+                .and_then(|(settings, presets)| {
+                    check_settings(&settings, known)?;
+
+                    for (name, preset_settings) in &presets {
+                        let name = name.as_str().unwrap_or_default();
+
+                        let preset_settings = preset_settings
+                            .as_mapping()
+                            .ok_or_else(|| anyhow!("The preset `{}` must be a YAML mapping", name))?;
+
+                        check_settings(preset_settings, known)
+                            .with_context(|| format!("In the preset `{}`", name))?;
+                    }
+
+                    Ok((settings, presets))
+                })
+                .with_context(|| format!("Invalid config file {}", p.display()))?;
+
+            config_file.settings = settings;
+            file_presets = presets;
+            config_file.path = Some(p);
+        }
+
+        let preset_name = match preset {
+            Some(n) => Some(n.to_string()),
+            None => config_file.get(PRESET_KEY),
+        };
+
+        if let Some(name) = preset_name {
+            let (builtin_presets, _) = parse_config(BUILTIN_PRESETS)?;
+
+            let preset_settings = lookup_preset(&file_presets, name.as_str())
+                .or_else(|| lookup_preset(&builtin_presets, name.as_str()))
+                .ok_or_else(|| anyhow!("No preset named `{}` could be found", name))?;
+
+            // settings directly in the file win over those from the preset
+            for (k, v) in preset_settings {
+                if !config_file.settings.contains_key(&k) {
+                    config_file.settings.insert(k, v);
+                }
+            }
+
+            config_file.preset = Some(name);
+        }
+
+        Ok(config_file)
+    }
+
+    /// Returns the value of the given setting rendered as a string (so that
+    /// it can be parsed the same way as a command line argument).
+    ///
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self.settings.get(&Value::String(name.to_string())) {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(Value::Number(n)) => Some(n.to_string()),
+            Some(Value::Bool(b)) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(DEFAULT_CONFIG_FILE))
+}
+
+/// Splits the YAML content of a config into the top level settings and
+/// the section of named presets.
+///
+fn parse_config(content: &str) -> Result<(Mapping, Mapping)> {
+    // an empty file is valid and just means no settings
+    let mut settings = match serde_yaml::from_str::<Value>(content)? {
+        Value::Mapping(m) => m,
+        Value::Null => Mapping::new(),
+        _ => return Err(anyhow!("Config must be a YAML mapping of settings")),
+    };
+
+    let presets = match settings.remove(&Value::String(PRESETS_KEY.to_string())) {
+        Some(Value::Mapping(m)) => m,
+        Some(_) => return Err(anyhow!("The `{}` section must be a YAML mapping", PRESETS_KEY)),
+        None => Mapping::new(),
+    };
+
+    Ok((settings, presets))
+}
+
+/// Checks that every setting is one we know about and has a single (scalar) value - as
+/// otherwise a misspelt setting would be silently ignored.
+///
+fn check_settings(settings: &Mapping, known: &[&str]) -> Result<()> {
+    let mut unknown = vec![];
+
+    for (k, v) in settings {
+        let name = match k.as_str() {
+            Some(name) if name == PRESET_KEY || known.contains(&name) => name,
+            _ => {
+                unknown.push(match k.as_str() {
+                    Some(name) => format!("`{}`", name),
+                    None => format!("{:?}", k),
+                });
+                continue;
+            }
+        };
+
+        match v {
+            Value::String(_) | Value::Number(_) | Value::Bool(_) => {}
+            _ => return Err(anyhow!("The setting `{}` must be a single value", name)),
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(anyhow!("Unknown settings {}", unknown.join(", ")));
+    }
+
+    Ok(())
+}
+
+fn lookup_preset(presets: &Mapping, name: &str) -> Option<Mapping> {
+    match presets.get(&Value::String(name.to_string())) {
+        Some(Value::Mapping(m)) => Some(m.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_file::{parse_config, ConfigFile};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const KNOWN: &[&str] = &[
+        "connections",
+        "block-size",
+        "network-buffer-size",
+        "disk-buffer-size",
+        "dns-desired-ips",
+        "dns-concurrent",
+        "dns-rounds",
+        "dns-round-delay",
+        "dns-server",
+        "fallocate",
+    ];
+
+    fn try_load_from_str(content: &str, preset: Option<&str>) -> anyhow::Result<ConfigFile> {
+        let mut file = NamedTempFile::new().unwrap();
+
+        file.write_all(content.as_bytes()).unwrap();
+
+        ConfigFile::load(Some(file.path()), preset, KNOWN)
+    }
+
+    fn load_from_str(content: &str, preset: Option<&str>) -> ConfigFile {
+        try_load_from_str(content, preset).unwrap()
+    }
+
+    #[test]
+    fn builtin_presets_parse() {
+        let (presets, _) = parse_config(super::BUILTIN_PRESETS).unwrap();
+
+        assert!(presets.len() >= 3);
+    }
+
+    #[test]
+    fn settings_read_as_strings() {
+        let cf = load_from_str("connections: 32\nfallocate: true\ndns-server: 1.1.1.1:53\n", None);
+
+        assert_eq!(cf.get("connections").unwrap(), "32");
+        assert_eq!(cf.get("fallocate").unwrap(), "true");
+        assert_eq!(cf.get("dns-server").unwrap(), "1.1.1.1:53");
+        assert!(cf.get("block-size").is_none());
+    }
+
+    #[test]
+    fn file_settings_win_over_builtin_preset() {
+        let cf = load_from_str("preset: nvme-instance\nconnections: 10\n", None);
+
+        assert_eq!(cf.preset.as_ref().unwrap(), "nvme-instance");
+        assert_eq!(cf.get("connections").unwrap(), "10");
+        assert_eq!(cf.get("disk-buffer-size").unwrap(), "4096");
+    }
+
+    #[test]
+    fn file_presets_replace_builtin_presets() {
+        let cf = load_from_str(
+            "presets:\n  nvme-instance:\n    connections: 99\n",
+            Some("nvme-instance"),
+        );
+
+        assert_eq!(cf.get("connections").unwrap(), "99");
+        assert!(cf.get("disk-buffer-size").is_none());
+    }
+
+    #[test]
+    fn unknown_preset_is_error() {
+        assert!(try_load_from_str("preset: no-such-preset\n", None).is_err());
+    }
+
+    #[test]
+    fn unknown_settings_are_reported() {
+        let e = try_load_from_str("conections: 32\nblock-size: 8\nfalocate: true\n", None)
+            .err()
+            .unwrap();
+
+        assert!(format!("{:#}", e).ends_with("Unknown settings `conections`, `falocate`"));
+
+        let e = try_load_from_str("presets:\n  mine:\n    blocksize: 8\n", None)
+            .err()
+            .unwrap();
+
+        assert!(format!("{:#}", e).contains("In the preset `mine`"));
+        assert!(format!("{:#}", e).ends_with("Unknown settings `blocksize`"));
+    }
+
+    #[test]
+    fn settings_must_be_single_values() {
+        let e = try_load_from_str("connections: [1, 2]\n", None).err().unwrap();
+
+        assert!(format!("{:#}", e).ends_with("The setting `connections` must be a single value"));
+
+        assert!(try_load_from_str("dns-server:\n  host: 1.1.1.1\n", None).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::StreamExt;
+use io_uring::{opcode, squeue, types, IoUring};
+use metrics_runtime::Sink;
+
+use crate::disk_writer::{DiskJob, OutputFiles, WriteBuffer};
+use crate::metric_names::METRIC_OVERALL_DISK_PWRITE;
+
+// the most submission queue entries we ask the kernel for
+const MAX_RING_ENTRIES: usize = 1024;
+
+/// A job that has been submitted to the ring and not yet completed - which owns the
+/// buffer being written so that it lives until the kernel is done with it.
+///
+struct InFlight {
+    offset: u64,
+    data: Option<WriteBuffer>,
+    done: oneshot::Sender<io::Result<()>>,
+    submitted: u64,
+}
+
+/// Starts a thread that takes jobs from the queue and submits them to an io_uring in
+/// batches - as many as are waiting (up to the size of the ring) per submission.
+///
+pub(crate) fn start(
+    receiver: mpsc::Receiver<DiskJob>,
+    files: Arc<OutputFiles>,
+    queue_depth: usize,
+    sink: &Sink,
+) -> io::Result<()> {
+    let entries = queue_depth.max(1).next_power_of_two().min(MAX_RING_ENTRIES);
+
+    // we create the ring here so that a kernel without io_uring is an error for our caller
+    let ring = IoUring::new(entries as u32)?;
+
+    let sink = sink.clone();
+
+    std::thread::Builder::new()
+        .name(String::from("disk-uring"))
+        .spawn(move || run_ring(ring, entries, receiver, &files, sink))?;
+
+    Ok(())
+}
+
+fn run_ring(
+    mut ring: IoUring,
+    entries: usize,
+    mut receiver: mpsc::Receiver<DiskJob>,
+    files: &OutputFiles,
+    mut sink: Sink,
+) {
+    let mut in_flight: HashMap<u64, InFlight> = HashMap::new();
+    let mut next_id = 0u64;
+    let mut closed = false;
+
+    loop {
+        // with nothing in flight we wait for work, and then take whatever else is
+        // already waiting so that it all goes in one submission
+        let mut jobs = vec![];
+
+        if in_flight.is_empty() {
+            match block_on(receiver.next()) {
+                Some(job) => jobs.push(job),
+                None => return,
+            }
+        }
+
+        while !closed && in_flight.len() + jobs.len() < entries {
+            match receiver.try_next() {
+                Ok(Some(job)) => jobs.push(job),
+                Ok(None) => closed = true,
+                Err(_) => break,
+            }
+        }
+
+        for job in jobs {
+            let id = next_id;
+            next_id += 1;
+
+            let (entry, in_flight_job) = match job {
+                DiskJob::Write { offset, data, done } => {
+                    let slice = data.as_slice();
+
+                    let fd = files.for_write(offset, slice.len()).as_raw_fd();
+
+                    let entry =
+                        opcode::Write::new(types::Fd(fd), slice.as_ptr(), slice.len() as u32)
+                            .offset64(offset as i64)
+                            .build();
+
+                    (
+                        entry,
+                        InFlight {
+                            offset,
+                            data: Some(data),
+                            done,
+                            submitted: sink.now(),
+                        },
+                    )
+                }
+                // the fsync waits for everything submitted before it to complete
+                DiskJob::Sync { done } => (
+                    opcode::Fsync::new(types::Fd(files.file.as_raw_fd()))
+                        .build()
+                        .flags(squeue::Flags::IO_DRAIN),
+                    InFlight {
+                        offset: 0,
+                        data: None,
+                        done,
+                        submitted: sink.now(),
+                    },
+                ),
+            };
+
+            // the buffer is owned by in_flight (and so doesn't move or get dropped) until
+            // we have seen the completion of its write - and the ring has room as we never
+            // have more in flight than its entries
+            unsafe {
+                ring.submission()
+                    .push(&entry.user_data(id))
+                    .expect("the io_uring submission queue is full");
+            }
+
+            in_flight.insert(id, in_flight_job);
+        }
+
+        if let Err(e) = ring.submit_and_wait(1) {
+            // without the ring working nothing in flight will ever complete
+            for (_, job) in in_flight.drain() {
+                let _ = job.done.send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+
+            return;
+        }
+
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+
+        for (id, result) in completed {
+            let job = match in_flight.remove(&id) {
+                Some(job) => job,
+                None => continue,
+            };
+
+            let outcome = match (&job.data, result) {
+                (_, result) if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+                (Some(data), written) => {
+                    let data = data.as_slice();
+                    let written = written as usize;
+
+                    sink.record_timing(METRIC_OVERALL_DISK_PWRITE, job.submitted, sink.now());
+
+                    // a short write is rare enough that we finish it off synchronously
+                    if written < data.len() {
+                        let offset = job.offset + written as u64;
+
+                        files
+                            .for_write(offset, data.len() - written)
+                            .write_all_at(&data[written..], offset)
+                    } else {
+                        Ok(())
+                    }
+                }
+                (None, _) => Ok(()),
+            };
+
+            // the block may have given up waiting for the outcome
+            let _ = job.done.send(outcome);
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt;
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::stream::FuturesUnordered;
+use futures::{ready, SinkExt, StreamExt};
+use metrics_runtime::Sink;
+use tokio::io::AsyncWrite;
+
+use crate::metric_names::{METRIC_OVERALL_DISK_FSYNC, METRIC_OVERALL_DISK_PWRITE};
+
+// direct IO never uses an alignment smaller than a page - so that the final (unaligned)
+// write through the page cache can't share a page with any direct write (this is also
+// the alignment we use if we can't find out what the file needs)
+#[cfg(target_os = "linux")]
+const MIN_DIRECT_ALIGNMENT: u64 = 4096;
+
+/// How we make our writes to disk.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskBackend {
+    // a pool of threads each making one pwrite at a time
+    Threads,
+
+    // a single thread submitting batches of writes to an io_uring (Linux only, and only
+    // when built with the io_uring feature)
+    IoUring,
+}
+
+impl FromStr for DiskBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "threads" => Ok(DiskBackend::Threads),
+            "io-uring" | "io_uring" => Ok(DiskBackend::IoUring),
+            _ => Err(format!("Unknown disk backend `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for DiskBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskBackend::Threads => write!(f, "threads"),
+            DiskBackend::IoUring => write!(f, "io-uring"),
+        }
+    }
+}
+
+/// Work for the disk backend - along with where to send the outcome once it is done.
+///
+pub(crate) enum DiskJob {
+    // write the buffer at the offset of the output file
+    Write {
+        offset: u64,
+        data: WriteBuffer,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+
+    // flush everything written so far through to the disk
+    Sync {
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// The output file - and, with direct IO, the same file opened with O_DIRECT.
+///
+pub(crate) struct OutputFiles {
+    pub(crate) file: File,
+    pub(crate) direct: Option<File>,
+    pub(crate) alignment: usize,
+}
+
+impl OutputFiles {
+    /// Returns the file a write should be made to - being the direct file as long as
+    /// the write is aligned.
+    ///
+    pub(crate) fn for_write(&self, offset: u64, length: usize) -> &File {
+        match &self.direct {
+            Some(direct) if offset % self.alignment as u64 == 0 && length % self.alignment == 0 => {
+                direct
+            }
+            _ => &self.file,
+        }
+    }
+}
+
+/// A buffer whose contents start at an address with the given alignment (as direct IO
+/// needs).
+///
+pub(crate) struct WriteBuffer {
+    storage: Vec<u8>,
+    start: usize,
+    len: usize,
+    capacity: usize,
+}
+
+impl WriteBuffer {
+    fn new(capacity: usize, alignment: usize) -> WriteBuffer {
+        // we never grow the storage so its contents never move
+        let storage = vec![0u8; capacity + alignment];
+        let start = storage.as_ptr().align_offset(alignment);
+
+        WriteBuffer {
+            storage,
+            start,
+            len: 0,
+            capacity,
+        }
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.storage[self.start..self.start + self.len]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    /// Appends as much of buf as will fit - returning how much that was.
+    ///
+    fn extend(&mut self, buf: &[u8]) -> usize {
+        let n = buf.len().min(self.capacity - self.len);
+        let end = self.start + self.len;
+
+        self.storage[end..end + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+
+        n
+    }
+}
+
+/// The output file opened for direct IO (bypassing the page cache), along with the
+/// alignment that the offset, length and memory of each direct write must have.
+///
+pub struct DirectFile {
+    file: File,
+    alignment: u64,
+}
+
+impl DirectFile {
+    /// Opens the (existing) output file with O_DIRECT.
+    ///
+    #[cfg(target_os = "linux")]
+    pub fn open(path: &Path) -> io::Result<DirectFile> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(nix::libc::O_DIRECT | nix::libc::O_NOFOLLOW)
+            .open(path)?;
+
+        let alignment = direct_alignment(&file).max(MIN_DIRECT_ALIGNMENT);
+
+        Ok(DirectFile { file, alignment })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_path: &Path) -> io::Result<DirectFile> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Direct IO is only supported on Linux",
+        ))
+    }
+
+    /// Returns the alignment that blocks must have to be written directly.
+    ///
+    pub fn alignment(&self) -> u64 {
+        self.alignment
+    }
+}
+
+/// Returns the alignment direct IO to the file needs - which the kernel tells us (from
+/// Linux 6.1), or failing that is the logical sector size of the device the file is on.
+///
+#[cfg(target_os = "linux")]
+fn direct_alignment(file: &File) -> u64 {
+    statx_dio_alignment(file)
+        .or_else(|| logical_sector_size(file))
+        .unwrap_or(MIN_DIRECT_ALIGNMENT)
+}
+
+/// Returns the alignment of both the offsets and the memory of direct IO to the file, as
+/// given by statx (or None if the kernel or filesystem can't tell us).
+///
+#[cfg(target_os = "linux")]
+fn statx_dio_alignment(file: &File) -> Option<u64> {
+    use nix::libc;
+
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+
+    let result = unsafe {
+        libc::statx(
+            file.as_raw_fd(),
+            b"\0".as_ptr() as *const libc::c_char,
+            libc::AT_EMPTY_PATH,
+            libc::STATX_DIOALIGN,
+            &mut stx,
+        )
+    };
+
+    // a zero alignment means the file doesn't support direct IO at all
+    if result != 0 || stx.stx_mask & libc::STATX_DIOALIGN == 0 || stx.stx_dio_offset_align == 0 {
+        return None;
+    }
+
+    Some(stx.stx_dio_offset_align.max(stx.stx_dio_mem_align) as u64)
+}
+
+/// Returns the logical sector size of the block device the file is on (as BLKSSZGET
+/// would, but via sysfs so that we needn't be able to open the device itself).
+///
+#[cfg(target_os = "linux")]
+fn logical_sector_size(file: &File) -> Option<u64> {
+    use nix::sys::stat::{major, minor};
+
+    let dev = file.metadata().ok()?.dev();
+
+    let device = format!("/sys/dev/block/{}:{}", major(dev), minor(dev));
+
+    // a partition has no queue of its own but shares that of its disk
+    ["queue", "../queue"]
+        .iter()
+        .filter_map(|queue| {
+            std::fs::read_to_string(format!("{}/{}/logical_block_size", device, queue)).ok()
+        })
+        .filter_map(|size| size.trim().parse::<u64>().ok())
+        .find(|size| size.is_power_of_two())
+}
+
+/// Writes to the output file from (non tokio runtime) threads - with every block writing
+/// to its own offset of the one shared file descriptor (using pwrite or io_uring).
+/// The queue of writes between the blocks and the threads is bounded so that a slow
+/// disk applies back pressure to the network rather than us buffering without limit.
+/// With direct IO every aligned write bypasses the page cache, and only the unaligned
+/// end of the file is written through it.
+///
+#[derive(Clone)]
+pub struct DiskWriter {
+    sender: mpsc::Sender<DiskJob>,
+
+    // the size of each write we make, and the alignment of the memory we make it from
+    buffer_size: usize,
+    alignment: usize,
+
+    sink: Sink,
+}
+
+impl DiskWriter {
+    /// Starts the disk backend writing to file (or to the direct file where possible),
+    /// with room for queue_depth writes of buffer_size bytes waiting for it. The threads
+    /// backend uses the given number of threads, whereas io_uring always uses one.
+    ///
+    pub fn start(
+        file: File,
+        direct: Option<DirectFile>,
+        backend: DiskBackend,
+        threads: usize,
+        queue_depth: usize,
+        buffer_size: usize,
+        sink: &Sink,
+    ) -> io::Result<DiskWriter> {
+        let alignment = direct.as_ref().map_or(1, |d| d.alignment) as usize;
+
+        let files = Arc::new(OutputFiles {
+            file,
+            direct: direct.map(|d| d.file),
+            alignment,
+        });
+
+        // every full buffer must be a whole number of aligned writes
+        let buffer_size = ((buffer_size.max(1) + alignment - 1) / alignment) * alignment;
+
+        // (the channel has an extra place for each sender on top of its buffer)
+        let (sender, receiver) = mpsc::channel::<DiskJob>(queue_depth.max(1) - 1);
+
+        match backend {
+            DiskBackend::Threads => {
+                let receiver = Arc::new(Mutex::new(receiver));
+
+                for thread in 0..threads.max(1) {
+                    let files = files.clone();
+                    let receiver = receiver.clone();
+                    let sink = sink.clone();
+
+                    std::thread::Builder::new()
+                        .name(format!("disk-writer-{}", thread))
+                        .spawn(move || run_disk_thread(&receiver, &files, sink))?;
+                }
+            }
+            DiskBackend::IoUring => start_io_uring(receiver, files, queue_depth, sink)?,
+        }
+
+        Ok(DiskWriter {
+            sender,
+            buffer_size,
+            alignment,
+            sink: sink.clone(),
+        })
+    }
+
+    /// Returns a writer for the block that starts at the given offset of the file.
+    ///
+    pub fn block_writer(&self, offset: u64) -> BlockWriter {
+        BlockWriter {
+            sender: self.sender.clone(),
+            offset,
+            buffer: WriteBuffer::new(self.buffer_size, self.alignment),
+            buffer_size: self.buffer_size,
+            alignment: self.alignment,
+            pending: FuturesUnordered::new(),
+        }
+    }
+
+    /// Flushes everything written to the file so far through to the disk. Writes only
+    /// count as written once the block they are part of has been flushed.
+    ///
+    pub async fn sync(&self) -> io::Result<()> {
+        let mut sink = self.sink.clone();
+
+        let started = sink.now();
+
+        let (done, outcome) = oneshot::channel();
+
+        self.sender
+            .clone()
+            .send(DiskJob::Sync { done })
+            .await
+            .map_err(disk_writer_stopped)?;
+
+        outcome.await.map_err(disk_writer_stopped)??;
+
+        sink.record_timing(METRIC_OVERALL_DISK_FSYNC, started, sink.now());
+
+        Ok(())
+    }
+}
+
+/// Makes the jobs of the queue one at a time (along with any other disk threads) until
+/// every sender has gone.
+///
+fn run_disk_thread(receiver: &Mutex<mpsc::Receiver<DiskJob>>, files: &OutputFiles, mut sink: Sink) {
+    loop {
+        // we only hold the lock while waiting for a job - not while doing it
+        let job = match block_on(receiver.lock().unwrap().next()) {
+            Some(job) => job,
+            None => return,
+        };
+
+        // the block may have given up waiting for the outcome (eg: after a network error)
+        match job {
+            DiskJob::Write { offset, data, done } => {
+                let started = sink.now();
+
+                let data = data.as_slice();
+
+                let result = files
+                    .for_write(offset, data.len())
+                    .write_all_at(data, offset);
+
+                sink.record_timing(METRIC_OVERALL_DISK_PWRITE, started, sink.now());
+
+                let _ = done.send(result);
+            }
+            DiskJob::Sync { done } => {
+                let _ = done.send(files.file.sync_all());
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+fn start_io_uring(
+    receiver: mpsc::Receiver<DiskJob>,
+    files: Arc<OutputFiles>,
+    queue_depth: usize,
+    sink: &Sink,
+) -> io::Result<()> {
+    crate::disk_uring::start(receiver, files, queue_depth, sink)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+fn start_io_uring(
+    _receiver: mpsc::Receiver<DiskJob>,
+    _files: Arc<OutputFiles>,
+    _queue_depth: usize,
+    _sink: &Sink,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "The io_uring disk backend needs Linux and s3bfg to be built with the io_uring feature",
+    ))
+}
+
+/// Writes a single block to its place in the output file via the disk threads. Data is
+/// gathered into buffers which are queued for writing as they fill - where flushing
+/// queues any partial buffer and then waits for every write of the block to be made.
+///
+pub struct BlockWriter {
+    sender: mpsc::Sender<DiskJob>,
+
+    // the offset in the file of the data in our buffer
+    offset: u64,
+    buffer: WriteBuffer,
+    buffer_size: usize,
+    alignment: usize,
+
+    // the outcomes of writes we have queued but not yet seen complete
+    pending: FuturesUnordered<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl BlockWriter {
+    /// Queues our buffer to be written - waiting for room in the queue if need be.
+    ///
+    fn poll_queue(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.sender.poll_ready(cx)).map_err(disk_writer_stopped)?;
+
+        let data = std::mem::replace(
+            &mut self.buffer,
+            WriteBuffer::new(self.buffer_size, self.alignment),
+        );
+        let (done, outcome) = oneshot::channel();
+
+        let length = data.len as u64;
+
+        self.sender
+            .start_send(DiskJob::Write {
+                offset: self.offset,
+                data,
+                done,
+            })
+            .map_err(disk_writer_stopped)?;
+
+        self.offset += length;
+        self.pending.push(outcome);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BlockWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+
+        if me.buffer.is_full() {
+            ready!(me.poll_queue(cx))?;
+        }
+
+        Poll::Ready(Ok(me.buffer.extend(buf)))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+
+        if !me.buffer.is_empty() {
+            ready!(me.poll_queue(cx))?;
+        }
+
+        while let Some(outcome) = ready!(me.pending.poll_next_unpin(cx)) {
+            outcome.map_err(disk_writer_stopped)??;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+fn disk_writer_stopped<E>(_: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "The disk writer threads have stopped",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_runtime::Receiver;
+    use tokio::io::AsyncWriteExt;
+
+    #[cfg(target_os = "linux")]
+    use crate::disk_writer::direct_alignment;
+    use crate::disk_writer::{DiskBackend, DiskWriter, WriteBuffer};
+
+    #[tokio::test]
+    async fn blocks_are_written_at_their_offsets() {
+        let sink = Receiver::builder().build().unwrap().sink();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        // small buffers and a short queue so that blocks have to wait on the threads
+        let writer = DiskWriter::start(
+            file.reopen().unwrap(),
+            None,
+            DiskBackend::Threads,
+            2,
+            2,
+            3,
+            &sink,
+        )
+        .unwrap();
+
+        let mut second = writer.block_writer(10);
+        second.write_all(b"0123456789").await.unwrap();
+
+        let mut first = writer.block_writer(0);
+        first.write_all(b"abcdefghij").await.unwrap();
+
+        first.flush().await.unwrap();
+        second.flush().await.unwrap();
+
+        writer.sync().await.unwrap();
+
+        assert_eq!(
+            std::fs::read(file.path()).unwrap(),
+            b"abcdefghij0123456789".to_vec()
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    #[tokio::test]
+    async fn blocks_are_written_at_their_offsets_with_io_uring() {
+        let sink = Receiver::builder().build().unwrap().sink();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        // a short queue so that blocks have to wait on the ring
+        let writer = DiskWriter::start(
+            file.reopen().unwrap(),
+            None,
+            DiskBackend::IoUring,
+            1,
+            2,
+            3,
+            &sink,
+        )
+        .unwrap();
+
+        let mut second = writer.block_writer(10);
+        second.write_all(b"0123456789").await.unwrap();
+
+        let mut first = writer.block_writer(0);
+        first.write_all(b"abcdefghij").await.unwrap();
+
+        first.flush().await.unwrap();
+        second.flush().await.unwrap();
+
+        // the sync goes through the ring too (after every write before it)
+        writer.sync().await.unwrap();
+
+        assert_eq!(
+            std::fs::read(file.path()).unwrap(),
+            b"abcdefghij0123456789".to_vec()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_alignment_is_a_power_of_two() {
+        let file = tempfile::tempfile().unwrap();
+
+        let alignment = direct_alignment(&file);
+
+        assert!(alignment.is_power_of_two());
+        assert!(alignment >= 512);
+    }
+
+    #[test]
+    fn buffers_are_aligned() {
+        let mut buffer = WriteBuffer::new(8192, 4096);
+
+        assert_eq!(buffer.as_slice().as_ptr() as usize % 4096, 0);
+
+        assert_eq!(buffer.extend(&[1u8; 5000]), 5000);
+        assert_eq!(buffer.extend(&[2u8; 5000]), 3192);
+        assert!(buffer.is_full());
+
+        assert_eq!(buffer.as_slice().len(), 8192);
+        assert_eq!(buffer.as_slice()[5000], 2);
+    }
+}
@@ -1,7 +1,9 @@
 use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
-use std::path::PathBuf;
+use std::os::unix::io::AsRawFd;
 use std::str;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use md5::{Digest, Md5};
@@ -9,17 +11,26 @@ use metrics_runtime::{Receiver, Sink};
 use regex::Regex;
 use rusoto_core::Region;
 use rusoto_credential::AwsCredentials;
+use rustls::ClientConfig;
 use simple_error::SimpleError;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio_rustls::*;
 
+use crate::config::Config;
 use crate::copy_exact::copy_exact;
+use crate::disk_writer::DiskWriter;
+use crate::metric_names::METRIC_OVERALL_ACTIVE_CONNECTIONS;
 use crate::metric_names::METRIC_OVERALL_TRANSFERRED_BYTES;
 use crate::metric_names::METRIC_SLOT_REQUEST;
 use crate::metric_names::METRIC_SLOT_SSL_SETUP;
 use crate::metric_names::METRIC_SLOT_TCP_SETUP;
 use crate::metric_names::METRIC_SLOT_TRANSFER_RATE_BYTES_PER_SEC;
+use crate::prewarmed_connections::{PrewarmedConnection, PrewarmedConnections};
+use crate::proxy::{connect, ProxySettings};
 use crate::s3_request_signed::{make_signed_get_part_request, make_signed_get_range_request};
+use crate::tcp_info::TcpInfoRecorder;
+use crate::tcp_socket::SocketSettings;
+use crate::trace_events::{BlockTrace, TraceRecorder};
 use std::sync::Arc;
 
 lazy_static! {
@@ -28,6 +39,19 @@ lazy_static! {
 }
 
 macro_rules! metric_it {
+    ($context:expr, $sink:ident, $record:expr, $trace:ident => $span:expr, $($s:stmt);+) => {
+        let before = $sink.now();
+        // should a statement return early with an error the span is recorded when dropped
+        let span = $trace.start($span, before, &$sink);
+        $(
+            $s
+        )*
+        let after = $sink.now();
+        if $record {
+            $sink.record_timing($context, before, after);
+        }
+        span.end(after);
+    };
     ($context:expr, $sink:ident, $record:expr, $($s:stmt);+) => {
         let before = $sink.now();
         $(
@@ -39,6 +63,150 @@ macro_rules! metric_it {
     }
 }
 
+/// A connection to S3 - which is TLS unless the user has turned TLS off.
+///
+pub trait S3Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> S3Stream for T {}
+
+/// Settings that apply equally to every block downloaded as part of a single transfer.
+///
+#[derive(Clone)]
+pub struct BlockSettings {
+    // if true we discard the data rather than writing it with the disk writer
+    pub memory_only: bool,
+
+    pub disk_writer: Option<DiskWriter>,
+
+    // if true we address our requests to the dualstack S3 hostname
+    pub dualstack: bool,
+
+    // the size of the buffer we use when reading from the network
+    pub network_buffer_size: usize,
+
+    // if present, we record the phases of every block into this
+    pub trace: Option<TraceRecorder>,
+
+    // the TLS config shared by all our connections (so they can resume TLS sessions)
+    pub tls_config: Arc<ClientConfig>,
+
+    // connections opened ahead of time for the first block of each slot
+    pub prewarmed: Option<Arc<PrewarmedConnections>>,
+
+    // if true we talk plain HTTP to S3 rather than HTTPS
+    pub no_tls: bool,
+
+    // the port we connect to S3 on (80 for plain HTTP, otherwise 443)
+    pub port: u16,
+
+    // the HTTP proxy we tunnel our connections through (if any)
+    pub proxy: Option<ProxySettings>,
+
+    // how we set up the TCP socket of each connection
+    pub sockets: SocketSettings,
+
+    // how often we sample TCP_INFO of each connection during the body transfer (if at all)
+    pub tcp_info_interval: Option<Duration>,
+}
+
+impl BlockSettings {
+    /// Returns the block settings that should be used for the run described by config.
+    ///
+    pub fn new(
+        config: &Config,
+        disk_writer: Option<DiskWriter>,
+        tls_config: Arc<ClientConfig>,
+        prewarmed: Option<Arc<PrewarmedConnections>>,
+        proxy: Option<ProxySettings>,
+        sockets: SocketSettings,
+    ) -> BlockSettings {
+        BlockSettings {
+            memory_only: config.memory_only,
+            disk_writer,
+            dualstack: config.dualstack,
+            network_buffer_size: (config.network_buffer_size_kibs * 1024) as usize,
+            trace: if config.trace_file.is_some() {
+                Some(TraceRecorder::new())
+            } else {
+                None
+            },
+            tls_config,
+            prewarmed,
+            no_tls: config.no_tls,
+            port: config.s3_port(),
+            proxy,
+            sockets,
+            tcp_info_interval: config.tcp_info_interval,
+        }
+    }
+
+    /// Returns the port we connect to S3 on.
+    ///
+    pub fn s3_port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// The outcome of successfully downloading a single block.
+///
+#[derive(Clone, Debug)]
+pub struct BlockResult {
+    // the slot the block was downloaded in
+    pub slot: usize,
+
+    // the S3 endpoint the block was downloaded from
+    pub s3_socket_addr: SocketAddr,
+
+    pub bytes: u64,
+
+    // nanoseconds spent on the whole block, and on just the TLS handshake
+    pub duration: u64,
+    pub tls_setup: u64,
+}
+
+impl BlockResult {
+    /// Returns the rate at which the block was downloaded.
+    ///
+    pub fn bytes_per_sec(&self) -> u64 {
+        if self.duration == 0 {
+            return 0;
+        }
+
+        (self.bytes as f64 / (self.duration as f64 / (1000.0 * 1000.0 * 1000.0))) as u64
+    }
+}
+
+// the connections currently open - which we publish as a gauge (the sink can only set
+// a gauge, not add to one)
+static ACTIVE_CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+
+/// Counts a connection as active in our metrics for as long as it is alive.
+///
+struct ActiveConnection {
+    sink: Sink,
+}
+
+impl ActiveConnection {
+    fn new(sink: &Sink) -> ActiveConnection {
+        let mut sink = sink.clone();
+
+        let active = ACTIVE_CONNECTIONS.fetch_add(1, Ordering::SeqCst) + 1;
+
+        sink.update_gauge(METRIC_OVERALL_ACTIVE_CONNECTIONS, active);
+
+        ActiveConnection { sink }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let active = ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::SeqCst) - 1;
+
+        self.sink
+            .update_gauge(METRIC_OVERALL_ACTIVE_CONNECTIONS, active);
+    }
+}
+
 /// Asynchronously do the actual work of transferring a single block of data from S3.
 /// Block can either be specified as a byte range of an object, or as a part number.
 ///
@@ -53,13 +221,13 @@ pub async fn download_block_work(
     start: u64,
     length: u64,
     part_number: u32,
-    memory_only: bool,
-    output_filename: Option<PathBuf>,
+    settings: &BlockSettings,
     output_start: u64,
-) -> anyhow::Result<usize, anyhow::Error> {
-    if !memory_only && output_filename.is_none() {
+    connection: Option<PrewarmedConnection>,
+) -> anyhow::Result<BlockResult, anyhow::Error> {
+    if !settings.memory_only && settings.disk_writer.is_none() {
         return Err(anyhow::Error::new(SimpleError::new(
-            "If memory_only is False then an output_filename must be specified",
+            "If memory_only is False then a disk_writer must be specified",
         )));
     }
 
@@ -75,13 +243,19 @@ pub async fn download_block_work(
 
     thread_sink.increment_counter("blocks_processed", 1);
 
-    metric_it!("overall-construct_signed_request", overall_sink, true,
+    let block_trace = match &settings.trace {
+        Some(recorder) => recorder.block(slot, start, length),
+        None => BlockTrace::disabled(),
+    };
+
+    metric_it!("overall-construct_signed_request", overall_sink, true, block_trace => "sign_request",
         let mut http_request: Vec<u8> = Vec::with_capacity(1024);
         let real_hostname =
             if part_number > 0 {
                 make_signed_get_part_request(
                     credentials,
                     s3_bucket_region,
+                    settings.dualstack,
                     s3_bucket_name,
                     s3_bucket_key,
                     part_number,
@@ -92,6 +266,7 @@ pub async fn download_block_work(
                 make_signed_get_range_request(
                     credentials,
                     s3_bucket_region,
+                    settings.dualstack,
                     s3_bucket_name,
                     s3_bucket_key,
                     start,
@@ -102,15 +277,10 @@ pub async fn download_block_work(
             }
     );
 
-    metric_it!("overall-tls_config_setup", overall_sink, true,
-        let mut tls_config = rustls::ClientConfig::new();
-        tls_config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS)
-    );
-
+    // the TLS config is shared so that we can resume TLS sessions rather than doing
+    // a full handshake every time
     metric_it!("overall-tls_connector_setup", overall_sink, false,
-        let tls_connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config))
+        let tls_connector = tokio_rustls::TlsConnector::from(settings.tls_config.clone())
     );
 
     metric_it!("overall-dnsname_ref_setup", overall_sink, false,
@@ -118,21 +288,56 @@ pub async fn download_block_work(
             tokio_rustls::webpki::DNSNameRef::try_from_ascii_str(real_hostname.as_str()).unwrap()
     );
 
-    //
-    // -- initial tcp stream connection
-    //
+    // we keep the fd of the socket so that we can sample its TCP_INFO as we go
+    let (stream, tls_setup, tcp_fd): (Box<dyn S3Stream>, u64, _) = match connection {
+        // we may have been given a connection that was opened ahead of time
+        Some(prewarmed) => {
+            // without TLS there was no handshake in setting it up
+            let tls_setup = if settings.no_tls {
+                0
+            } else {
+                prewarmed.setup.as_nanos() as u64
+            };
 
-    metric_it!(METRIC_SLOT_TCP_SETUP, overall_sink, true,
-        let tcp_stream = tokio::net::TcpStream::connect(s3_socket_addr).await?
-    );
+            (prewarmed.stream, tls_setup, prewarmed.tcp_fd)
+        }
+        None => {
+            //
+            // -- initial tcp stream connection
+            //
+
+            metric_it!(METRIC_SLOT_TCP_SETUP, overall_sink, true, block_trace => "tcp_connect",
+                let tcp_stream = connect(
+                    settings.proxy.as_ref(),
+                    &settings.sockets,
+                    slot,
+                    s3_socket_addr,
+                    real_hostname.as_str(),
+                )
+                .await?
+            );
 
-    //
-    // -- do SSL handshake and setup SSL stream
-    //
+            let tcp_fd = tcp_stream.as_raw_fd();
 
-    metric_it!(METRIC_SLOT_SSL_SETUP, overall_sink, true,
-        let stream = tls_connector.connect(domain, tcp_stream).await?
-    );
+            if settings.no_tls {
+                (Box::new(tcp_stream), 0, tcp_fd)
+            } else {
+                //
+                // -- do SSL handshake and setup SSL stream
+                //
+
+                let tls_started = overall_sink.now();
+
+                metric_it!(METRIC_SLOT_SSL_SETUP, overall_sink, true, block_trace => "tls_handshake",
+                    let stream = tls_connector.connect(domain, tcp_stream).await?
+                );
+
+                (Box::new(stream), overall_sink.now() - tls_started, tcp_fd)
+            }
+        }
+    };
+
+    let _active_connection = ActiveConnection::new(overall_sink);
 
     //
     // -- split our io into reading and writing streams
@@ -143,6 +348,8 @@ pub async fn download_block_work(
     //
     // -- blast out our request for data
     //
+    let request_started = slot_sink.now();
+
     metric_it!(
         METRIC_SLOT_REQUEST,
         slot_sink,
@@ -155,7 +362,7 @@ pub async fn download_block_work(
     //
 
     // most of our network reads on linux seem to be in the ~20k range so a 256k buffer for the reader seems plenty
-    let mut buf_reader = tokio::io::BufReader::with_capacity(256 * 1024, reader);
+    let mut buf_reader = tokio::io::BufReader::with_capacity(settings.network_buffer_size, reader);
 
     // parse the first line of the HTTP response - the status line - which in our S3 case is about all we care
     // about for now
@@ -189,6 +396,8 @@ pub async fn download_block_work(
         }
     }
 
+    block_trace.span("time_to_first_byte", request_started, slot_sink.now());
+
     let mut header_count = 0;
 
     loop {
@@ -223,35 +432,43 @@ pub async fn download_block_work(
 
     let copied_bytes;
 
-    if memory_only {
+    // the stream (and so the fd) outlives our sampling of it
+    let mut tcp_info = TcpInfoRecorder::new(tcp_fd, &slot_sink);
+
+    if settings.memory_only {
         // we use a tokio sink to send the data to nowhere..
-        copied_bytes = copy_exact(
-            &mut slot_sink,
-            &mut buf_reader,
-            &mut tokio::io::sink(),
-            length,
-        )
-        .await?;
+        metric_it!("overall-body_transfer", slot_sink, false, block_trace => "body_transfer",
+            copied_bytes = tcp_info
+                .record_during(
+                    settings.tcp_info_interval,
+                    copy_exact(&mut slot_sink, &mut buf_reader, &mut tokio::io::sink(), length),
+                )
+                .await?
+        );
     } else {
-        let mut oo = std::fs::OpenOptions::new();
-        oo.write(true);
-        oo.create(false);
-
-        let mut file_writer = tokio::fs::OpenOptions::from(oo)
-            .open(output_filename.unwrap())
-            .await?;
-
-        file_writer
-            .seek(std::io::SeekFrom::Start(output_start))
-            .await?;
-
-        let mut buf_writer = tokio::io::BufWriter::with_capacity(512 * 1024, file_writer);
+        // our writes are made (with pwrite) at our offset of the shared output file by
+        // the disk threads - with us waiting whenever their queue is full
+        let mut block_writer = settings
+            .disk_writer
+            .as_ref()
+            .unwrap()
+            .block_writer(output_start);
 
         // note that copy_exact is responsible for generating some metrics via the passed
         // in sink (including the overall bytes transferred counter)
-        copied_bytes = copy_exact(&mut slot_sink, &mut buf_reader, &mut buf_writer, length).await?;
+        metric_it!("overall-body_transfer", slot_sink, false, block_trace => "body_transfer",
+            copied_bytes = tcp_info
+                .record_during(
+                    settings.tcp_info_interval,
+                    copy_exact(&mut slot_sink, &mut buf_reader, &mut block_writer, length),
+                )
+                .await?
+        );
 
-        buf_writer.flush();
+        // the block only counts as written once every one of its writes has been made
+        metric_it!("overall-disk_flush", slot_sink, false, block_trace => "disk_flush",
+            block_writer.flush().await?
+        );
 
         // TODO: assert block checksums if possible
         // whilst we could possibly compute this hash during the copy_exact routine, we really
@@ -271,22 +488,25 @@ pub async fn download_block_work(
         "Amount recorded as having being copied did not match the length of the block"
     );
 
-    // compute a per slot metric of how fast we are copying things
-    {
-        let elapsed_seconds = (slot_sink.now() - now_start) as f64 / (1000.0 * 1000.0 * 1000.0);
+    let now_end = slot_sink.now();
 
-        if elapsed_seconds > 0.0 {
-            let bytes_per_sec = copied_bytes as f64 / elapsed_seconds;
+    let result = BlockResult {
+        slot,
+        s3_socket_addr,
+        bytes: copied_bytes,
+        duration: now_end - now_start,
+        tls_setup,
+    };
 
-            slot_sink.record_value(
-                METRIC_SLOT_TRANSFER_RATE_BYTES_PER_SEC,
-                bytes_per_sec as u64,
-            );
-        }
+    // compute a per slot metric of how fast we are copying things
+    if result.duration > 0 {
+        slot_sink.record_value(METRIC_SLOT_TRANSFER_RATE_BYTES_PER_SEC, result.bytes_per_sec());
     }
 
     // keep an overall record that tracks our total bytes copied
     overall_sink.increment_counter(METRIC_OVERALL_TRANSFERRED_BYTES, copied_bytes);
 
-    Ok(slot)
+    block_trace.span("block", now_start, now_end);
+
+    Ok(result)
 }
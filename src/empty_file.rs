@@ -1,37 +1,497 @@
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io;
-
-#[cfg(target_os = "linux")]
-use nix::fcntl::fallocate;
-#[cfg(target_os = "linux")]
-use nix::fcntl::FallocateFlags;
+use std::os::unix::fs::OpenOptionsExt;
 #[cfg(target_os = "linux")]
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[cfg(target_os = "linux")]
-pub fn create_empty_target_file(write_filename: &Path, size: u64) -> Result<File, io::Error> {
-    // because we want to let fallocate do its best we want to always work on a new file (disabled)
+use nix::fcntl::{fallocate, posix_fallocate, FallocateFlags};
+use nix::sys::statvfs::statvfs;
+
+/// The preallocation we use when no other is asked for - fallocate() where we have it.
+///
+#[cfg(target_os = "linux")]
+pub const DEFAULT_PREALLOCATE: &str = "fallocate";
+#[cfg(not(target_os = "linux"))]
+pub const DEFAULT_PREALLOCATE: &str = "sparse";
+
+/// How we reserve the space of the destination file before writing any of it.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Preallocate {
+    // allocate the blocks without zeroing them (Linux only, and the filesystem must
+    // support it)
+    Fallocate,
+
+    // allocate the blocks, with libc writing zeros if the filesystem can't do better
+    PosixFallocate,
+
+    // set the length of the file without allocating any blocks
+    Sparse,
+
+    // leave the file to grow as the blocks are written
+    None,
+}
+
+impl FromStr for Preallocate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fallocate" => Ok(Preallocate::Fallocate),
+            "posix-fallocate" | "posix_fallocate" => Ok(Preallocate::PosixFallocate),
+            "sparse" | "truncate" => Ok(Preallocate::Sparse),
+            "none" => Ok(Preallocate::None),
+            _ => Err(format!("Unknown preallocation `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Preallocate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Preallocate::Fallocate => write!(f, "fallocate"),
+            Preallocate::PosixFallocate => write!(f, "posix-fallocate"),
+            Preallocate::Sparse => write!(f, "sparse"),
+            Preallocate::None => write!(f, "none"),
+        }
+    }
+}
+
+/// What we do when the destination already exists.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clobber {
+    // replace it
+    Replace,
+
+    // fail rather than replace it
+    Refuse,
+
+    // keep it alongside with a ~ on the end of its name (or .~N~ if that is taken), and
+    // then replace it
+    Backup,
+}
+
+impl fmt::Display for Clobber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clobber::Replace => write!(f, "replace"),
+            Clobber::Refuse => write!(f, "no-clobber"),
+            Clobber::Backup => write!(f, "backup"),
+        }
+    }
+}
+
+/// The destination of a download - which we write as a temporary file in the same
+/// directory and only rename to the final path once the download has succeeded, so
+/// that a failed run never leaves a partial file where the real one should be.
+///
+pub struct TargetFile {
+    path: PathBuf,
+    temporary: PathBuf,
+
+    // until the temporary file is renamed into place we remove it when dropped
+    committed: bool,
+}
+
+impl TargetFile {
+    /// Returns the path of the temporary file we are writing to.
+    ///
+    pub fn temporary_path(&self) -> &Path {
+        self.temporary.as_path()
+    }
+
+    /// Moves the finished file into place at the final path - dealing with any file
+    /// already there as told to. A symlink at the final path is itself replaced (or
+    /// backed up) rather than followed.
+    ///
+    /// The file is flushed to disk before it is moved, and the move is flushed after,
+    /// so that after a crash the final path holds either the old file or all of the new.
+    ///
+    pub fn commit(mut self, clobber: Clobber) -> Result<(), io::Error> {
+        File::open(&self.temporary)?.sync_all()?;
+
+        self.move_into_place(clobber)?;
+
+        self.committed = true;
+
+        sync_parent(&self.path)
+    }
+
+    fn move_into_place(&self, clobber: Clobber) -> Result<(), io::Error> {
+        match clobber {
+            Clobber::Replace => std::fs::rename(&self.temporary, &self.path)?,
+            Clobber::Refuse => {
+                // unlike a rename a hard link fails rather than replace an existing file
+                // - though not every filesystem has them
+                match std::fs::hard_link(&self.temporary, &self.path) {
+                    Ok(()) => {
+                        let _ = std::fs::remove_file(&self.temporary);
+
+                        return Ok(());
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        return Err(already_exists(&self.path))
+                    }
+                    Err(_) => {
+                        if std::fs::symlink_metadata(&self.path).is_ok() {
+                            return Err(already_exists(&self.path));
+                        }
+
+                        std::fs::rename(&self.temporary, &self.path)?
+                    }
+                }
+            }
+            Clobber::Backup => {
+                if std::fs::symlink_metadata(&self.path).is_ok() {
+                    back_up(&self.path)?;
+                }
+
+                std::fs::rename(&self.temporary, &self.path)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TargetFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temporary);
+        }
+    }
+}
+
+/// Returns the error for a destination that exists when we were told not to replace it.
+///
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "The destination {} already exists (and --no-clobber was given)",
+            path.display()
+        ),
+    )
+}
+
+/// Returns the path we keep an existing destination at when backing it up - the first
+/// being `<name>~`, with `<name>.~1~`, `<name>.~2~` and so on should that be taken.
+///
+pub fn backup_path(path: &Path, attempt: u32) -> PathBuf {
+    let mut backup = OsString::from(path.as_os_str());
+
+    if attempt == 0 {
+        backup.push("~");
+    } else {
+        backup.push(format!(".~{}~", attempt));
+    }
+
+    PathBuf::from(backup)
+}
+
+/// Keeps the existing file at path under the first backup path that is free - never
+/// replacing an earlier backup - and returns that backup path.
+///
+fn back_up(path: &Path) -> Result<PathBuf, io::Error> {
+    for attempt in 0.. {
+        let backup = backup_path(path, attempt);
+
+        // as with refusing to clobber, a hard link fails rather than replace an existing
+        // backup - with the rename of the new file into place then unlinking the original
+        match std::fs::hard_link(path, &backup) {
+            Ok(()) => return Ok(backup),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => {
+                if std::fs::symlink_metadata(&backup).is_ok() {
+                    continue;
+                }
+
+                std::fs::rename(path, &backup)?;
+
+                return Ok(backup);
+            }
+        }
+    }
+
+    unreachable!("we will run out of disk before we run out of backup paths")
+}
+
+/// Flushes the directory that path is in - which is what makes a rename into it durable.
+///
+fn sync_parent(path: &Path) -> Result<(), io::Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    File::open(parent)?.sync_all()
+}
+
+/// Returns the path of the temporary file we write to before it becomes path - hidden
+/// and in the same directory (so that the final rename stays within the filesystem).
+///
+fn temporary_path(path: &Path) -> Result<PathBuf, io::Error> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The destination {} is not a file name", path.display()),
+        )
+    })?;
+
+    let mut temporary = OsString::from(".");
+    temporary.push(name);
+    temporary.push(format!(".s3bfg-{}", std::process::id()));
+
+    Ok(path.with_file_name(temporary))
+}
+
+/// Creates the temporary file we are going to write the destination to, and reserves
+/// size bytes for it using the given preallocation.
+///
+pub fn create_empty_target_file(
+    write_filename: &Path,
+    size: u64,
+    preallocate: Preallocate,
+) -> Result<(File, TargetFile), io::Error> {
+    let temporary = temporary_path(write_filename)?;
+
+    // we only ever write to a file we have just created ourselves - never to one
+    // that was already there, or to wherever a symlink there points
     let file = OpenOptions::new()
         .write(true)
-        .create(true)
-        .open(write_filename)?;
+        .create_new(true)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(&temporary)?;
+
+    let target = TargetFile {
+        path: write_filename.to_path_buf(),
+        temporary,
+        committed: false,
+    };
+
+    match preallocate {
+        Preallocate::Fallocate | Preallocate::PosixFallocate => allocate(&file, size, preallocate)?,
+        Preallocate::Sparse => file.set_len(size)?,
+        Preallocate::None => {}
+    }
+
+    Ok((file, target))
+}
 
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, size: u64, preallocate: Preallocate) -> Result<(), io::Error> {
     let fd = file.as_raw_fd();
 
-    // for linux we have the added ability to allocate the full size of the file
-    // without any actual zero initialising
-    fallocate(fd, FallocateFlags::empty(), 0, size as i64);
+    let result = match preallocate {
+        Preallocate::PosixFallocate => posix_fallocate(fd, 0, size as i64),
+        _ => fallocate(fd, FallocateFlags::empty(), 0, size as i64).map(|_| ()),
+    };
 
-    Ok(file)
+    result.map_err(|e| {
+        let e = match e.as_errno() {
+            Some(errno) => io::Error::from_raw_os_error(errno as i32),
+            None => io::Error::new(io::ErrorKind::Other, e),
+        };
+
+        preallocate_error(preallocate, size, e)
+    })
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn create_empty_target_file(write_filename: &Path, _size: u64) -> Result<File, io::Error> {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(write_filename)?;
+fn allocate(_file: &File, size: u64, preallocate: Preallocate) -> Result<(), io::Error> {
+    Err(preallocate_error(
+        preallocate,
+        size,
+        io::Error::from_raw_os_error(nix::libc::EOPNOTSUPP),
+    ))
+}
+
+/// Returns an error explaining why preallocation failed - and what to do about it.
+///
+fn preallocate_error(preallocate: Preallocate, size: u64, e: io::Error) -> io::Error {
+    let hint = match e.raw_os_error() {
+        Some(nix::libc::ENOSPC) => "there is not enough free space",
+        Some(nix::libc::EOPNOTSUPP) => {
+            "it is not supported here (try --preallocate sparse or --preallocate none)"
+        }
+        Some(nix::libc::EFBIG) => "the file would be too large for the filesystem",
+        _ => "it failed",
+    };
+
+    io::Error::new(
+        e.kind(),
+        format!(
+            "Unable to preallocate {} bytes with {} as {} ({})",
+            size, preallocate, hint, e
+        ),
+    )
+}
+
+/// Checks that the filesystem that write_filename is on has room for a file of size
+/// bytes - not counting the space of any file already there, as it remains until ours
+/// replaces it.
+///
+pub fn check_free_space(write_filename: &Path, size: u64) -> Result<(), io::Error> {
+    let directory = match write_filename.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let stats = statvfs(directory).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Unable to find the free space of {} ({})",
+                directory.display(),
+                e
+            ),
+        )
+    })?;
+
+    let available = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+
+    if available < size {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Not enough free space for {} bytes on the filesystem of {} (only {} bytes are available)",
+                size,
+                directory.display(),
+                available
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::empty_file::{
+        backup_path, check_free_space, create_empty_target_file, Clobber, Preallocate,
+        DEFAULT_PREALLOCATE,
+    };
+
+    #[test]
+    fn parses_preallocations() {
+        assert_eq!("fallocate".parse(), Ok(Preallocate::Fallocate));
+        assert_eq!("posix_fallocate".parse(), Ok(Preallocate::PosixFallocate));
+        assert_eq!("truncate".parse(), Ok(Preallocate::Sparse));
+        assert_eq!("NONE".parse(), Ok(Preallocate::None));
+        assert!("zeros".parse::<Preallocate>().is_err());
+
+        // and what we display can be parsed back
+        let default: Preallocate = DEFAULT_PREALLOCATE.parse().unwrap();
+        assert_eq!(default.to_string().parse(), Ok(default));
+    }
+
+    #[test]
+    fn sparse_and_none_set_the_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        let (file, _target) =
+            create_empty_target_file(&path, 1_000_000, Preallocate::Sparse).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 1_000_000);
+
+        let path = dir.path().join("out2");
+
+        let (file, _target) =
+            create_empty_target_file(&path, 1_000_000, Preallocate::None).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn only_a_committed_file_reaches_the_final_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        // a target that is dropped (ie: a failed download) leaves nothing behind
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+        let temporary = target.temporary_path().to_path_buf();
+
+        assert!(temporary.exists());
+        assert!(!path.exists());
+
+        drop(target);
+
+        assert!(!temporary.exists());
+        assert!(!path.exists());
+
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+
+        target.commit(Clobber::Replace).unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 10);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn existing_files_are_refused_or_backed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        std::fs::write(&path, b"original").unwrap();
+
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+
+        let e = target.commit(Clobber::Refuse).err().unwrap();
+        assert!(e.to_string().contains("already exists"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+
+        target.commit(Clobber::Backup).unwrap();
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 10);
+        assert_eq!(std::fs::read(backup_path(&path, 0)).unwrap(), b"original");
+
+        // an earlier backup is never replaced by a later one
+        std::fs::write(&path, b"second").unwrap();
+
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+
+        target.commit(Clobber::Backup).unwrap();
+
+        assert_eq!(std::fs::read(backup_path(&path, 0)).unwrap(), b"original");
+        assert_eq!(std::fs::read(backup_path(&path, 1)).unwrap(), b"second");
+        assert!(backup_path(&path, 1).to_string_lossy().ends_with("out.~1~"));
+    }
+
+    #[test]
+    fn symlinks_at_the_destination_are_replaced_not_followed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let elsewhere = dir.path().join("elsewhere");
+
+        std::fs::write(&elsewhere, b"precious").unwrap();
+        std::os::unix::fs::symlink(&elsewhere, &path).unwrap();
+
+        let (_, target) = create_empty_target_file(&path, 10, Preallocate::Sparse).unwrap();
+
+        target.commit(Clobber::Replace).unwrap();
+
+        assert!(!std::fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read(&elsewhere).unwrap(), b"precious");
+    }
+
+    #[test]
+    fn free_space_check_refuses_impossible_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        assert!(check_free_space(&path, 1).is_ok());
 
-    Ok(file)
+        let e = check_free_space(&path, u64::MAX).unwrap_err();
+        assert!(e.to_string().contains("Not enough free space"));
+    }
 }
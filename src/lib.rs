@@ -1,25 +1,58 @@
+// the report builds its JSON with one (deeply nested) json! macro
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate lazy_static;
 extern crate nix;
 #[macro_use]
 extern crate simple_error;
 
-// we are not actually building a library for general usage - this is just
-// exposing the code used by the CLI tests to the integration tests
+// the transfer engine is usable as a library via `Transfer` - the CLI is just
+// a thin wrapper around it (the other modules are exposed for the integration tests)
 pub mod asynchronous_download;
 pub mod built_info;
 pub mod config;
+pub mod config_file;
 pub mod copy_exact;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+pub mod disk_uring;
+pub mod disk_writer;
 pub mod download_block;
 pub mod empty_file;
+pub mod metric_exporters;
 pub mod metric_names;
 pub mod metric_observer_progress;
+pub mod metric_observer_prometheus;
+pub mod metric_observer_report;
+pub mod metric_observer_statsd;
+pub mod metric_observer_timeseries;
 pub mod metric_observer_ui;
+pub mod prewarmed_connections;
+pub mod proxy;
+pub mod report;
+pub mod s3_etag;
 pub mod s3_info;
+pub mod s3_ip_cache;
+pub mod s3_ip_dns;
+pub mod s3_ip_policy;
 pub mod s3_ip_pool;
+pub mod s3_ip_probe;
 pub mod s3_request_signed;
 pub mod s3_uris;
 pub mod setup_aws_credentials;
 pub mod setup_metrics;
+pub mod setup_tls;
 pub mod setup_tokio;
+pub mod tcp_info;
+pub mod tcp_socket;
+pub mod trace_events;
+pub mod transfer;
 pub mod ui_console;
+pub mod ui_timeseries;
+
+pub use crate::s3_ip_dns::DnsProtocol;
+pub use crate::s3_ip_policy::IpPolicy;
+pub use crate::s3_ip_pool::IpVersion;
+pub use crate::transfer::{
+    EndpointSource, LocatedObject, Transfer, TransferBuilder, TransferError, TransferStats,
+};
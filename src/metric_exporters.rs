@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+
+use metrics_core::Observe;
+use metrics_runtime::Controller;
+
+use crate::config::Config;
+use crate::metric_observer_prometheus::PrometheusObserver;
+use crate::metric_observer_statsd::StatsdObserver;
+
+// the prefix of all the metric names we export
+const EXPORT_PREFIX: &str = "s3bfg";
+
+// keep our StatsD packets within a typical network MTU
+const STATSD_MAX_PACKET_BYTES: usize = 1400;
+
+/// Periodically exports our metrics to any of the external systems
+/// that have been configured - with a final export when finished.
+///
+pub struct MetricsExporters {
+    stop: Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+impl MetricsExporters {
+    /// Starts a (non tokio runtime) thread that exports the metrics, returning
+    /// None if there are no exporters configured.
+    ///
+    pub fn start(controller: Controller, config: &Config) -> io::Result<Option<MetricsExporters>> {
+        if config.prometheus_textfile.is_none() && config.statsd_address.is_none() {
+            return Ok(None);
+        }
+
+        let prometheus = config.prometheus_textfile.clone();
+
+        let statsd = match &config.statsd_address {
+            Some(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address.as_str())?;
+                Some((address.clone(), socket))
+            }
+            None => None,
+        };
+
+        let interval = config.metrics_export_interval;
+
+        let (stop, stopped) = channel();
+
+        let worker = std::thread::spawn(move || {
+            let mut previous_counters = HashMap::new();
+
+            loop {
+                let finished = match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    _ => true,
+                };
+
+                // exporting is best effort - we don't want to interrupt the transfer
+                if let Some(path) = &prometheus {
+                    if let Err(e) = export_prometheus(&controller, path) {
+                        eprintln!("Failed to write metrics to {} - {}", path.display(), e);
+                    }
+                }
+                if let Some((address, socket)) = &statsd {
+                    if let Err(e) = export_statsd(&controller, socket, &mut previous_counters) {
+                        eprintln!("Failed to send metrics to {} - {}", address, e);
+                    }
+                }
+
+                if finished {
+                    break;
+                }
+            }
+        });
+
+        Ok(Some(MetricsExporters { stop, worker }))
+    }
+
+    /// Does a final export and then stops the exporting thread.
+    ///
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.worker.join();
+    }
+}
+
+/// Writes all the metrics to the given path - via a temporary file and rename so
+/// that the node_exporter textfile collector never sees a partial file.
+///
+fn export_prometheus(controller: &Controller, path: &Path) -> io::Result<()> {
+    let mut observer = PrometheusObserver::new(EXPORT_PREFIX);
+
+    controller.observe(&mut observer);
+
+    let mut temp_path = PathBuf::from(path);
+    temp_path.set_extension("prom.tmp");
+
+    fs::write(&temp_path, observer.render())?;
+    fs::rename(&temp_path, path)
+}
+
+/// Sends all the metrics to StatsD, batching lines into packets.
+///
+fn export_statsd(
+    controller: &Controller,
+    socket: &UdpSocket,
+    previous_counters: &mut HashMap<String, u64>,
+) -> io::Result<()> {
+    let mut observer = StatsdObserver::new(EXPORT_PREFIX, previous_counters);
+
+    controller.observe(&mut observer);
+
+    let mut packet = String::new();
+
+    for line in observer.lines() {
+        if !packet.is_empty() && packet.len() + line.len() + 1 > STATSD_MAX_PACKET_BYTES {
+            socket.send(packet.as_bytes())?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line.as_str());
+    }
+
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes())?;
+    }
+
+    Ok(())
+}
@@ -1,7 +1,11 @@
 pub const METRIC_OVERALL_TRANSFERRED_BYTES: &str = "overall-transferred_bytes";
+pub const METRIC_OVERALL_BLOCK_ERRORS: &str = "overall-block_errors";
+pub const METRIC_OVERALL_ACTIVE_CONNECTIONS: &str = "overall-active_connections";
 
 pub const METRIC_OVERALL_NETWORK_READ_OP_SIZE: &str = "network_read_size";
 pub const METRIC_OVERALL_DISK_WRITE_OP_SIZE: &str = "disk_write_size";
+pub const METRIC_OVERALL_DISK_PWRITE: &str = "overall-disk_pwrite";
+pub const METRIC_OVERALL_DISK_FSYNC: &str = "overall-disk_fsync";
 
 pub const BYTES_PER_SEC_SUFFIX: &str = "bytes_per_sec";
 pub const TIMING_NANOSEC_SUFFIX: &str = "timing_nanosec";
@@ -11,8 +15,16 @@ pub const METRIC_SLOT_TRANSFER_RATE_BYTES_PER_SEC: &str = concat!("transfer_", "
 pub const METRIC_SLOT_NETWORK_RATE_BYTES_PER_SEC: &str = concat!("network_", "bytes_per_sec");
 pub const METRIC_SLOT_DISK_RATE_BYTES_PER_SEC: &str = concat!("disk_", "bytes_per_sec");
 
+pub const METRIC_SLOT_TRANSFERRED_BYTES: &str = "transferred_bytes";
+
 pub const METRIC_SLOT_STATE_SETUP: &str = "slot_state_setup_timing_nanosec";
 pub const METRIC_SLOT_TCP_SETUP: &str = "slot_tcp_setup_timing_nanosec";
 pub const METRIC_SLOT_SSL_SETUP: &str = "slot_ssl_setup_timing_sec";
 pub const METRIC_SLOT_REQUEST: &str = "slot_request_timing_sec";
 pub const METRIC_SLOT_RESPONSE: &str = "slot_response_timing_sec";
+
+// sampled from TCP_INFO of the connection of each slot
+pub const METRIC_SLOT_TCP_RCV_RTT: &str = concat!("tcp_rcv_rtt_", "timing_nanosec");
+pub const METRIC_SLOT_TCP_RCV_RTT_MICROS: &str = "tcp_rcv_rtt_micros";
+pub const METRIC_SLOT_TCP_RCV_SPACE_BYTES: &str = "tcp_rcv_space_bytes";
+pub const METRIC_SLOT_TCP_RECEIVED_BYTES: &str = "tcp_received_bytes";
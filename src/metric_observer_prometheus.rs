@@ -0,0 +1,190 @@
+use hdrhistogram::Histogram;
+use metrics_core::{Key, Observer};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// the scopes we turn into labels rather than leave as part of the metric name
+const LABELLED_SCOPES: [&str; 2] = ["slot", "thread"];
+
+// the quantiles we expose for each histogram (as a prometheus summary)
+const QUANTILES: [f64; 4] = [0.0, 0.5, 0.9, 1.0];
+
+/// Observes metrics in the Prometheus text exposition format - suitable
+/// for the node_exporter textfile collector.
+///
+/// Metrics scoped to a slot or thread (ie `slot-3.transfer_bytes_per_sec`) are
+/// rendered as a single metric with a label (ie `s3bfg_transfer_bytes_per_sec{slot="3"}`).
+///
+pub struct PrometheusObserver {
+    prefix: String,
+    counters: BTreeMap<String, BTreeMap<String, u64>>,
+    gauges: BTreeMap<String, BTreeMap<String, i64>>,
+    histograms: BTreeMap<String, BTreeMap<String, Histogram<u64>>>,
+}
+
+impl PrometheusObserver {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            histograms: BTreeMap::new(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, series) in self.counters.iter() {
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (labels, value) in series {
+                writeln!(out, "{}{} {}", name, braced(labels), value).unwrap();
+            }
+        }
+
+        for (name, series) in self.gauges.iter() {
+            writeln!(out, "# TYPE {} gauge", name).unwrap();
+            for (labels, value) in series {
+                writeln!(out, "{}{} {}", name, braced(labels), value).unwrap();
+            }
+        }
+
+        for (name, series) in self.histograms.iter() {
+            writeln!(out, "# TYPE {} summary", name).unwrap();
+            for (labels, h) in series {
+                for q in QUANTILES.iter() {
+                    let quantile_label = format!("quantile=\"{}\"", q);
+                    let all_labels = if labels.is_empty() {
+                        quantile_label
+                    } else {
+                        format!("{},{}", labels, quantile_label)
+                    };
+                    writeln!(
+                        out,
+                        "{}{{{}}} {}",
+                        name,
+                        all_labels,
+                        h.value_at_quantile(*q)
+                    )
+                    .unwrap();
+                }
+                // the histogram does not keep an exact sum so this is approximate
+                writeln!(
+                    out,
+                    "{}_sum{} {}",
+                    name,
+                    braced(labels),
+                    (h.mean() * h.len() as f64) as u64
+                )
+                .unwrap();
+                writeln!(out, "{}_count{} {}", name, braced(labels), h.len()).unwrap();
+            }
+        }
+
+        out
+    }
+
+    fn name_and_labels(&self, key: &Key) -> (String, String) {
+        prometheus_name_and_labels(self.prefix.as_str(), key.name().as_ref())
+    }
+}
+
+impl Observer for PrometheusObserver {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        let (name, labels) = self.name_and_labels(&key);
+
+        self.counters.entry(name).or_default().insert(labels, value);
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        let (name, labels) = self.name_and_labels(&key);
+
+        self.gauges.entry(name).or_default().insert(labels, value);
+    }
+
+    fn observe_histogram(&mut self, key: Key, values: &[u64]) {
+        let (name, labels) = self.name_and_labels(&key);
+
+        let entry = self
+            .histograms
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_insert_with(|| Histogram::<u64>::new(3).expect("failed to create histogram"));
+
+        for value in values {
+            entry
+                .record(*value)
+                .expect("failed to observe histogram value");
+        }
+    }
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+/// Converts one of our metric names into a valid Prometheus metric name and set
+/// of labels.
+///
+fn prometheus_name_and_labels(prefix: &str, name: &str) -> (String, String) {
+    let mut labels = vec![];
+    let mut parts = vec![prefix.to_string()];
+
+    for scope in name.split('.') {
+        // scopes like `slot-3` become labels
+        let labelled = LABELLED_SCOPES.iter().find(|l| {
+            scope.len() > l.len() + 1
+                && scope.starts_with(*l)
+                && scope[l.len()..].starts_with('-')
+                && scope[l.len() + 1..].chars().all(|c| c.is_ascii_digit())
+        });
+
+        match labelled {
+            Some(l) => labels.push(format!("{}=\"{}\"", l, &scope[l.len() + 1..])),
+            None => parts.push(scope.to_string()),
+        }
+    }
+
+    let sanitised = parts
+        .join("_")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+
+    (sanitised, labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metric_observer_prometheus::prometheus_name_and_labels;
+
+    #[test]
+    fn overall_names() {
+        assert_eq!(
+            prometheus_name_and_labels("s3bfg", "overall-transferred_bytes"),
+            (String::from("s3bfg_overall_transferred_bytes"), String::new())
+        );
+    }
+
+    #[test]
+    fn slot_names() {
+        assert_eq!(
+            prometheus_name_and_labels("s3bfg", "slot-12.transfer_bytes_per_sec"),
+            (
+                String::from("s3bfg_transfer_bytes_per_sec"),
+                String::from("slot=\"12\"")
+            )
+        );
+        // something that looks like a slot but isn't
+        assert_eq!(
+            prometheus_name_and_labels("s3bfg", "slotty-1.x"),
+            (String::from("s3bfg_slotty_1_x"), String::new())
+        );
+    }
+}
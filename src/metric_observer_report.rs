@@ -0,0 +1,151 @@
+use hdrhistogram::Histogram;
+use metrics_core::{Key, Observer};
+use serde_json::{json, Map, Value};
+
+use std::collections::BTreeMap;
+
+// the scope prefix that the per slot sinks use
+const SLOT_SCOPE_PREFIX: &str = "slot-";
+
+/// Observes metrics so that they can be included in a structured report
+/// of the run.
+///
+pub struct ReportObserver {
+    counters: BTreeMap<String, u64>,
+    gauges: BTreeMap<String, i64>,
+    histograms: BTreeMap<String, Histogram<u64>>,
+}
+
+impl ReportObserver {
+    pub fn new() -> Self {
+        Self {
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            histograms: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the observed value of a counter (or 0 if it was never recorded).
+    ///
+    pub fn counter(&self, name: &str) -> u64 {
+        *self.counters.get(name).unwrap_or(&0)
+    }
+
+    /// Returns the metrics that are not specific to a slot.
+    ///
+    pub fn overall_json(&self) -> Value {
+        let mut overall = Map::new();
+
+        for (name, value) in self.counters.iter().filter(|c| slot_of(c.0).is_none()) {
+            overall.insert(name.clone(), json!(value));
+        }
+        for (name, value) in self.gauges.iter().filter(|g| slot_of(g.0).is_none()) {
+            overall.insert(name.clone(), json!(value));
+        }
+        for (name, h) in self.histograms.iter().filter(|h| slot_of(h.0).is_none()) {
+            overall.insert(name.clone(), histogram_json(h));
+        }
+
+        Value::Object(overall)
+    }
+
+    /// Returns the metrics that were recorded by each slot, as a list ordered by slot.
+    ///
+    pub fn slots_json(&self) -> Value {
+        let mut slots: BTreeMap<u32, Map<String, Value>> = BTreeMap::new();
+
+        for (name, value) in self.counters.iter() {
+            if let Some((slot, metric)) = slot_of(name) {
+                slots.entry(slot).or_default().insert(metric, json!(value));
+            }
+        }
+        for (name, value) in self.gauges.iter() {
+            if let Some((slot, metric)) = slot_of(name) {
+                slots.entry(slot).or_default().insert(metric, json!(value));
+            }
+        }
+        for (name, h) in self.histograms.iter() {
+            if let Some((slot, metric)) = slot_of(name) {
+                slots.entry(slot).or_default().insert(metric, histogram_json(h));
+            }
+        }
+
+        Value::Array(
+            slots
+                .into_iter()
+                .map(|(slot, mut metrics)| {
+                    metrics.insert(String::from("slot"), json!(slot));
+                    Value::Object(metrics)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Observer for ReportObserver {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        self.counters.insert(key.name().to_string(), value);
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        self.gauges.insert(key.name().to_string(), value);
+    }
+
+    fn observe_histogram(&mut self, key: Key, values: &[u64]) {
+        let entry = self
+            .histograms
+            .entry(key.name().to_string())
+            .or_insert_with(|| Histogram::<u64>::new(3).expect("failed to create histogram"));
+
+        for value in values {
+            entry
+                .record(*value)
+                .expect("failed to observe histogram value");
+        }
+    }
+}
+
+/// If the metric name is scoped to a slot (ie `slot-3.transfer_bytes_per_sec`) returns
+/// the slot number and the unscoped name.
+///
+fn slot_of(name: &str) -> Option<(u32, String)> {
+    let mut parts = name.splitn(2, '.');
+
+    let scope = parts.next()?;
+    let metric = parts.next()?;
+
+    if !scope.starts_with(SLOT_SCOPE_PREFIX) {
+        return None;
+    }
+
+    let slot = scope[SLOT_SCOPE_PREFIX.len()..].parse::<u32>().ok()?;
+
+    Some((slot, metric.to_string()))
+}
+
+fn histogram_json(h: &Histogram<u64>) -> Value {
+    json!({
+        "count": h.len(),
+        "mean": h.mean(),
+        "min": h.min(),
+        "p50": h.value_at_quantile(0.5),
+        "p90": h.value_at_quantile(0.9),
+        "max": h.max(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metric_observer_report::slot_of;
+
+    #[test]
+    fn slot_scoped_names() {
+        assert_eq!(
+            slot_of("slot-12.transfer_bytes_per_sec"),
+            Some((12, String::from("transfer_bytes_per_sec")))
+        );
+        assert_eq!(slot_of("overall-transferred_bytes"), None);
+        assert_eq!(slot_of("thread-3.blocks_processed"), None);
+        assert_eq!(slot_of("slot-x.something"), None);
+    }
+}
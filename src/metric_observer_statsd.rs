@@ -0,0 +1,85 @@
+use hdrhistogram::Histogram;
+use metrics_core::{Key, Observer};
+
+use std::collections::HashMap;
+
+/// Observes metrics as StatsD lines.
+///
+/// Our counters are cumulative over the whole run so they are sent as
+/// the delta since the last observation (given to us in `previous_counters`). Histograms
+/// are summarised and sent as gauges.
+///
+pub struct StatsdObserver<'a> {
+    prefix: String,
+    previous_counters: &'a mut HashMap<String, u64>,
+    lines: Vec<String>,
+}
+
+impl<'a> StatsdObserver<'a> {
+    pub fn new(prefix: &str, previous_counters: &'a mut HashMap<String, u64>) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            previous_counters,
+            lines: vec![],
+        }
+    }
+
+    /// Returns the StatsD lines for everything that was observed.
+    ///
+    pub fn lines(self) -> Vec<String> {
+        self.lines
+    }
+
+    fn statsd_name(&self, key: &Key) -> String {
+        let name = key
+            .name()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+
+        format!("{}.{}", self.prefix, name)
+    }
+}
+
+impl<'a> Observer for StatsdObserver<'a> {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        let name = self.statsd_name(&key);
+
+        let previous = self.previous_counters.insert(name.clone(), value).unwrap_or(0);
+
+        if value > previous {
+            self.lines.push(format!("{}:{}|c", name, value - previous));
+        }
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        let name = self.statsd_name(&key);
+
+        self.lines.push(format!("{}:{}|g", name, value));
+    }
+
+    fn observe_histogram(&mut self, key: Key, values: &[u64]) {
+        if values.is_empty() {
+            return;
+        }
+
+        let name = self.statsd_name(&key);
+
+        let mut h = Histogram::<u64>::new(3).expect("failed to create histogram");
+
+        for value in values {
+            h.record(*value).expect("failed to observe histogram value");
+        }
+
+        self.lines.push(format!("{}.mean:{}|g", name, h.mean() as u64));
+        self.lines.push(format!("{}.p50:{}|g", name, h.value_at_quantile(0.5)));
+        self.lines.push(format!("{}.p90:{}|g", name, h.value_at_quantile(0.9)));
+        self.lines.push(format!("{}.max:{}|g", name, h.max()));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::metric_names::{
+    METRIC_OVERALL_ACTIVE_CONNECTIONS, METRIC_OVERALL_BLOCK_ERRORS,
+    METRIC_OVERALL_TRANSFERRED_BYTES, METRIC_SLOT_TCP_RCV_RTT_MICROS,
+    METRIC_SLOT_TCP_RCV_SPACE_BYTES, METRIC_SLOT_TCP_RECEIVED_BYTES, METRIC_SLOT_TRANSFERRED_BYTES,
+};
+use metrics_core::{Key, Observer};
+
+use std::collections::HashMap;
+
+/// Observes the handful of metrics that we record each second into a time series.
+///
+pub struct TimeseriesObserver {
+    pub transferred: u64,
+    pub active_connections: i64,
+    pub errors: u64,
+
+    // bytes transferred keyed by slot number
+    pub slot_transferred: HashMap<usize, u64>,
+
+    // the latest TCP_INFO sample of each slot's connection, and the bytes received
+    // by all its connections, keyed by slot number
+    pub slot_rcv_rtt_micros: HashMap<usize, i64>,
+    pub slot_rcv_space: HashMap<usize, i64>,
+    pub slot_tcp_received: HashMap<usize, u64>,
+}
+
+impl TimeseriesObserver {
+    pub fn new() -> Self {
+        Self {
+            transferred: 0,
+            active_connections: 0,
+            errors: 0,
+            slot_transferred: HashMap::new(),
+            slot_rcv_rtt_micros: HashMap::new(),
+            slot_rcv_space: HashMap::new(),
+            slot_tcp_received: HashMap::new(),
+        }
+    }
+}
+
+impl Observer for TimeseriesObserver {
+    fn observe_counter(&mut self, key: Key, value: u64) {
+        let (name, _labels) = key.into_parts();
+
+        if name.eq(METRIC_OVERALL_TRANSFERRED_BYTES) {
+            self.transferred = value;
+        } else if name.eq(METRIC_OVERALL_BLOCK_ERRORS) {
+            self.errors = value;
+        } else if let Some((slot, metric)) = slot_of(&name) {
+            if metric == METRIC_SLOT_TRANSFERRED_BYTES {
+                self.slot_transferred.insert(slot, value);
+            } else if metric == METRIC_SLOT_TCP_RECEIVED_BYTES {
+                self.slot_tcp_received.insert(slot, value);
+            }
+        }
+    }
+
+    fn observe_gauge(&mut self, key: Key, value: i64) {
+        let (name, _labels) = key.into_parts();
+
+        if name.eq(METRIC_OVERALL_ACTIVE_CONNECTIONS) {
+            self.active_connections = value;
+        } else if let Some((slot, metric)) = slot_of(&name) {
+            if metric == METRIC_SLOT_TCP_RCV_RTT_MICROS {
+                self.slot_rcv_rtt_micros.insert(slot, value);
+            } else if metric == METRIC_SLOT_TCP_RCV_SPACE_BYTES {
+                self.slot_rcv_space.insert(slot, value);
+            }
+        }
+    }
+
+    fn observe_histogram(&mut self, _key: Key, _values: &[u64]) {}
+}
+
+/// If the metric name is scoped to a slot (ie `slot-3.transferred_bytes`) returns the
+/// slot number and the unscoped name.
+///
+fn slot_of(name: &str) -> Option<(usize, &str)> {
+    let rest = name.strip_prefix("slot-")?;
+    let mut parts = rest.splitn(2, '.');
+
+    let slot = parts.next()?.parse::<usize>().ok()?;
+
+    Some((slot, parts.next()?))
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use rustls::ClientConfig;
+use tokio::time::timeout;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use crate::download_block::S3Stream;
+use crate::proxy::{connect, ProxySettings};
+use crate::s3_ip_pool::S3IpPool;
+use crate::tcp_socket::SocketSettings;
+
+// S3 will close idle connections so we don't use any connection we opened longer ago than this
+const MAX_IDLE: Duration = Duration::from_secs(15);
+
+/// A connection to S3 that was opened (and TLS handshaken, unless we are talking plain
+/// HTTP) ahead of time.
+///
+pub struct PrewarmedConnection {
+    pub s3_socket_addr: SocketAddr,
+
+    pub stream: Box<dyn S3Stream>,
+
+    // the fd of the TCP socket underneath the stream
+    pub tcp_fd: RawFd,
+
+    // how long the TCP connect and any TLS handshake took
+    pub setup: Duration,
+
+    opened: Instant,
+}
+
+/// Connections to S3 opened ahead of time - one for each of the first blocks
+/// in each slot.
+///
+pub struct PrewarmedConnections {
+    connections: Mutex<HashMap<usize, PrewarmedConnection>>,
+}
+
+impl PrewarmedConnections {
+    /// Opens a connection for each slot (in parallel) to the given port of an endpoint
+    /// chosen from the pool - giving up on any connection that takes longer than
+    /// max_duration. Without a TLS config the connections are plain HTTP.
+    ///
+    pub async fn open(
+        pool: &S3IpPool,
+        slots: usize,
+        port: u16,
+        hostname: &str,
+        tls_config: Option<Arc<ClientConfig>>,
+        proxy: Option<&ProxySettings>,
+        sockets: &SocketSettings,
+        max_duration: Duration,
+    ) -> PrewarmedConnections {
+        let tls_connector = tls_config.map(TlsConnector::from);
+
+        let opens = (0..slots).map(|slot| {
+            let (ip, _) = pool.choose_ip(slot);
+
+            open_connection(
+                slot,
+                SocketAddr::new(ip, port),
+                tls_connector.as_ref(),
+                hostname,
+                proxy,
+                sockets,
+                max_duration,
+            )
+        });
+
+        let connections = join_all(opens)
+            .await
+            .into_iter()
+            .enumerate()
+            .filter_map(|(slot, c)| c.map(|c| (slot, c)))
+            .collect();
+
+        PrewarmedConnections {
+            connections: Mutex::new(connections),
+        }
+    }
+
+    /// Returns the connection opened for the given slot (if there was one and it
+    /// is still recent enough to use).
+    ///
+    pub fn take(&self, slot: usize) -> Option<PrewarmedConnection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .remove(&slot)
+            .filter(|c| c.opened.elapsed() < MAX_IDLE)
+    }
+
+    /// Returns the number of connections that are waiting to be used.
+    ///
+    pub fn count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+}
+
+async fn open_connection(
+    slot: usize,
+    s3_socket_addr: SocketAddr,
+    tls_connector: Option<&TlsConnector>,
+    hostname: &str,
+    proxy: Option<&ProxySettings>,
+    sockets: &SocketSettings,
+    max_duration: Duration,
+) -> Option<PrewarmedConnection> {
+    let domain = DNSNameRef::try_from_ascii_str(hostname).ok()?;
+
+    let started = Instant::now();
+
+    let handshake = async {
+        let tcp_stream = connect(proxy, sockets, slot, s3_socket_addr, hostname).await?;
+
+        let tcp_fd = tcp_stream.as_raw_fd();
+
+        let stream: Box<dyn S3Stream> = match tls_connector {
+            Some(tls_connector) => Box::new(tls_connector.connect(domain, tcp_stream).await?),
+            None => Box::new(tcp_stream),
+        };
+
+        Ok::<_, io::Error>((stream, tcp_fd))
+    };
+
+    match timeout(max_duration, handshake).await {
+        Ok(Ok((stream, tcp_fd))) => Some(PrewarmedConnection {
+            s3_socket_addr,
+            stream,
+            tcp_fd,
+            setup: started.elapsed(),
+            opened: Instant::now(),
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,358 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use metrics_core::Observe;
+use metrics_runtime::Controller;
+use serde_json::{json, Value};
+
+use crate::built_info;
+use crate::config::Config;
+use crate::metric_names::{METRIC_OVERALL_BLOCK_ERRORS, METRIC_OVERALL_TRANSFERRED_BYTES};
+use crate::metric_observer_report::ReportObserver;
+use crate::s3_etag::EtagCheck;
+use crate::s3_info::S3ObjectDetails;
+use crate::s3_ip_pool::S3IpStats;
+use crate::transfer::{TransferError, TransferStats};
+
+/// The formats in which we can report the outcome of a run.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportFormat {
+    // human readable (and not guaranteed to be stable)
+    Text,
+
+    // a single JSON document
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("Unknown report format `{}`", s)),
+        }
+    }
+}
+
+/// Returns a structured report of a run - whether the transfer succeeded or not. The
+/// object details are only available if we got as far as finding the object.
+///
+pub fn create_report(
+    config: &Config,
+    object: Option<&S3ObjectDetails>,
+    outcome: Result<&TransferStats, &TransferError>,
+    controller: &Controller,
+) -> Value {
+    let mut observer = ReportObserver::new();
+
+    controller.observe(&mut observer);
+
+    // a transfer that failed part way through still has statistics of its endpoints
+    let stats = match outcome {
+        Ok(stats) => Some(stats),
+        Err(e) => e.stats(),
+    };
+
+    // why any blocks failed (whether or not that then failed the transfer), and then
+    // why the transfer failed
+    let mut errors: Vec<Value> = stats
+        .map(|stats| stats.block_errors.iter().map(|e| json!(e)).collect())
+        .unwrap_or_default();
+
+    if let Err(e) = outcome {
+        errors.push(json!(e.to_string()));
+    }
+
+    // a failed transfer only copied some of the object - as counted by our metrics
+    let bytes = match outcome {
+        Ok(stats) => Some(stats.bytes),
+        Err(e) => e
+            .stats()
+            .map(|_| observer.counter(METRIC_OVERALL_TRANSFERRED_BYTES)),
+    };
+
+    json!({
+        "version": built_info::PKG_VERSION,
+        "success": outcome.is_ok(),
+        "object": object.map(object_json),
+        "config": config_json(config),
+        "timings": stats.map(|stats| json!({
+            "dns_seconds": stats.dns_duration.as_secs_f64(),
+            "probe_seconds": stats.probe_duration.as_secs_f64(),
+            "total_seconds": stats.duration.as_secs_f64(),
+        })),
+        "bytes": bytes,
+        "blocks": stats.map(|stats| stats.blocks),
+        "rate_mibs_per_sec": stats.map(|stats| stats.rate_mibs_per_sec()),
+        "endpoints_source": stats.map(|stats| stats.s3_endpoints_source.to_string()),
+        "endpoints": stats.map(|stats| {
+            stats
+                .s3_endpoint_stats
+                .iter()
+                .map(|(ip, ip_stats)| endpoint_json(ip, ip_stats))
+                .collect::<Vec<_>>()
+        }),
+        "slots": observer.slots_json(),
+        "metrics": observer.overall_json(),
+        "errors": errors,
+        "block_errors": observer.counter(METRIC_OVERALL_BLOCK_ERRORS),
+        "verification": verification_json(stats.and_then(|stats| stats.verification.as_ref())),
+    })
+}
+
+/// Returns how the copy compared with the ETag of the object - or None if we weren't
+/// asked to check.
+///
+fn verification_json(verification: Option<&EtagCheck>) -> Value {
+    let verification = match verification {
+        Some(verification) => verification,
+        None => return Value::Null,
+    };
+
+    let (etag, computed, reason) = match verification {
+        EtagCheck::Matched(etag) => (Some(etag), Some(etag), None),
+        EtagCheck::Mismatched(etag, computed) => (Some(etag), Some(computed), None),
+        EtagCheck::Unverifiable(reason) => (None, None, Some(reason)),
+    };
+
+    json!({
+        "method": "etag",
+        "etag": etag,
+        "computed": computed,
+        "verified": verification.verified(),
+        "reason": reason,
+    })
+}
+
+/// Returns a human readable table of how each S3 endpoint (IP address) performed.
+///
+pub fn endpoints_table(stats: &TransferStats) -> String {
+    let mut table = format!(
+        "{:<40} {:>8} {:>8} {:>10} {:>10} {:>10} {:>8} {:>8} {:>6}\n",
+        "endpoint", "assigned", "blocks", "mean MiB/s", "p50 MiB/s", "p90 MiB/s", "tls ms", "connect ms", "errors"
+    );
+
+    for (ip, ip_stats) in stats.s3_endpoint_stats.iter() {
+        table.push_str(
+            format!(
+                "{:<40} {:>8} {:>8} {:>10} {:>10} {:>10} {:>8} {:>8} {:>6}\n",
+                if ip_stats.retired {
+                    format!("{} (retired)", ip)
+                } else {
+                    ip.clone()
+                },
+                ip_stats.usage,
+                ip_stats.blocks,
+                optional_mibs(ip_stats.throughput_mean()),
+                optional_mibs(ip_stats.throughput_at_quantile(0.5).map(|v| v as f64)),
+                optional_mibs(ip_stats.throughput_at_quantile(0.9).map(|v| v as f64)),
+                ip_stats
+                    .tls_setup_mean()
+                    .map(|d| format!("{:.1}", d.as_secs_f64() * 1000.0))
+                    .unwrap_or_else(|| "-".to_string()),
+                ip_stats
+                    .probe_rtt
+                    .map(|d| format!("{:.1}", d.as_secs_f64() * 1000.0))
+                    .unwrap_or_else(|| "-".to_string()),
+                ip_stats.errors
+            )
+            .as_str(),
+        );
+    }
+
+    table
+}
+
+fn optional_mibs(bytes_per_sec: Option<f64>) -> String {
+    bytes_per_sec
+        .map(|v| format!("{:.1}", v / (1024.0 * 1024.0)))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Writes the report as JSON to the given file.
+///
+pub fn write_report(report: &Value, path: &Path) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+
+    serde_json::to_writer_pretty(file, report)?;
+
+    Ok(())
+}
+
+fn object_json(object: &S3ObjectDetails) -> Value {
+    json!({
+        "bucket": object.bucket,
+        "key": object.key,
+        "region": object.region.name(),
+        "size_in_bytes": object.size_in_bytes,
+        "etag": object.etag,
+        "parts": object.part_count(),
+    })
+}
+
+fn endpoint_json(ip: &str, ip_stats: &S3IpStats) -> Value {
+    json!({
+        "ip": ip,
+        "assigned": ip_stats.usage,
+        "retired": ip_stats.retired,
+        "blocks": ip_stats.blocks,
+        "bytes": ip_stats.bytes,
+        "errors": ip_stats.errors,
+        "throughput_bytes_per_sec": {
+            "mean": ip_stats.throughput_mean(),
+            "p50": ip_stats.throughput_at_quantile(0.5),
+            "p90": ip_stats.throughput_at_quantile(0.9),
+        },
+        "tls_setup_seconds_mean": ip_stats.tls_setup_mean().map(|d| d.as_secs_f64()),
+        "probe_connect_seconds": ip_stats.probe_rtt.map(|d| d.as_secs_f64()),
+        "probe_tls_seconds": ip_stats.probe_tls.map(|d| d.as_secs_f64()),
+    })
+}
+
+fn config_json(config: &Config) -> Value {
+    json!({
+        "output": config.output_write_filename.as_ref().map(|p| p.display().to_string()),
+        "memory_only": config.memory_only,
+        "profile": config.aws_profile,
+        "dns_protocol": format!("{:?}", config.dns_protocol).to_ascii_lowercase(),
+        "dns_server": config.dns_server,
+        "dns_https_url": config.dns_https_url,
+        "dns_desired_ips": config.dns_desired_ips,
+        "dns_concurrent": config.dns_concurrent,
+        "dns_rounds": config.dns_rounds,
+        "dns_round_delay_millis": config.dns_round_delay.as_millis() as u64,
+        "dns_refresh_interval_seconds": config.dns_refresh_interval.map(|d| d.as_secs_f64()),
+        "ip_version": format!("{:?}", config.ip_version).to_ascii_lowercase(),
+        "dualstack": config.dualstack,
+        "s3_ips": config.s3_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+        "ip_cache": config.ip_cache_file.as_ref().map(|p| p.display().to_string()),
+        "ip_cache_ttl_seconds": config.ip_cache_ttl.as_secs(),
+        "ip_policy": config.ip_policy.to_string(),
+        "probe": config.probe,
+        "probe_timeout_seconds": config.probe_timeout.as_secs_f64(),
+        "prewarm": config.prewarm,
+        "no_tls": config.no_tls,
+        "ca_bundle": config.ca_bundle.as_ref().map(|p| p.display().to_string()),
+        "native_roots": config.native_roots,
+        "client_cert": config.client_cert.as_ref().map(|p| p.display().to_string()),
+        "proxy": config.proxy.is_some(),
+        "socket_recv_buffer_size_kibs": config.socket_recv_buffer_size_kibs,
+        "tcp_nodelay": config.tcp_nodelay,
+        "tcp_congestion": config.tcp_congestion,
+        "bind": config.bind,
+        "tcp_info_interval_seconds": config.tcp_info_interval.map(|i| i.as_secs_f64()),
+        "connections": config.s3_connections,
+        "tokio_core_threads": config.tokio_core_threads,
+        "tokio_max_threads": config.tokio_max_threads,
+        "tokio_basic": config.tokio_basic,
+        "block_size_mibs": config.block_size_mibs,
+        "network_buffer_size_kibs": config.network_buffer_size_kibs,
+        "disk_buffer_size_kibs": config.disk_buffer_size_kibs,
+        "disk_backend": config.disk_backend.to_string(),
+        "disk_threads": config.disk_threads,
+        "disk_queue_depth": config.disk_queue_depth,
+        "fsync": config.fsync,
+        "direct_io": config.direct_io,
+        "verify": config.verify,
+        "preallocate": config.preallocate.to_string(),
+        "clobber": config.clobber.to_string(),
+        "instance_type": config.instance_type,
+        "prometheus_textfile": config.prometheus_textfile.as_ref().map(|p| p.display().to_string()),
+        "statsd": config.statsd_address,
+        "timeseries": config.timeseries_file.as_ref().map(|p| p.display().to_string()),
+        "trace": config.trace_file.as_ref().map(|p| p.display().to_string()),
+        "config_file": config.config_file.as_ref().map(|p| p.display().to_string()),
+        "preset": config.preset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use metrics_runtime::Receiver;
+
+    use crate::config::Config;
+    use crate::metric_names::METRIC_OVERALL_TRANSFERRED_BYTES;
+    use crate::report::{create_report, verification_json};
+    use crate::s3_etag::EtagCheck;
+    use crate::s3_ip_pool::S3IpStats;
+    use crate::transfer::{EndpointSource, TransferError, TransferStats};
+
+    #[test]
+    fn failures_part_way_through_are_reported_in_full() {
+        let receiver = Receiver::builder().build().unwrap();
+
+        receiver
+            .sink()
+            .increment_counter(METRIC_OVERALL_TRANSFERRED_BYTES, 4096);
+
+        let mut endpoint = S3IpStats::new();
+        endpoint.errors = 3;
+
+        let mut s3_endpoint_stats = BTreeMap::new();
+        s3_endpoint_stats.insert(String::from("52.216.1.1"), endpoint);
+
+        let error = TransferError::Incomplete(
+            anyhow!("connection reset"),
+            Box::new(TransferStats {
+                bytes: 4096,
+                blocks: 4,
+                s3_endpoints: 1,
+                s3_endpoints_source: EndpointSource::Dns,
+                s3_endpoint_stats,
+                block_errors: vec![String::from("52.216.1.1:443 - connection reset")],
+                dns_duration: Duration::from_millis(100),
+                probe_duration: Duration::from_millis(0),
+                duration: Duration::from_secs(2),
+                verification: None,
+            }),
+        );
+
+        let report = create_report(&Config::default(), None, Err(&error), &receiver.controller());
+
+        assert_eq!(report["success"], false);
+        assert_eq!(report["bytes"], 4096);
+        assert_eq!(report["blocks"], 4);
+        assert_eq!(report["timings"]["total_seconds"], 2.0);
+        assert_eq!(report["endpoints"][0]["errors"], 3);
+        assert_eq!(report["errors"].as_array().unwrap().len(), 2);
+
+        // failing before the transfer leaves nothing to report on but the error
+        let error = TransferError::InvalidLocation(String::from("no source"));
+
+        let report = create_report(&Config::default(), None, Err(&error), &receiver.controller());
+
+        assert!(report["bytes"].is_null());
+        assert!(report["endpoints"].is_null());
+        assert_eq!(report["errors"][0], "no source");
+    }
+
+    #[test]
+    fn verification_is_only_claimed_when_the_etag_was_checked() {
+        assert!(verification_json(None).is_null());
+
+        let matched = verification_json(Some(&EtagCheck::Matched(String::from("abc-2"))));
+
+        assert_eq!(matched["verified"], true);
+        assert_eq!(matched["computed"], "abc-2");
+
+        let mismatched = verification_json(Some(&EtagCheck::Mismatched(
+            String::from("abc-2"),
+            String::from("def-2"),
+        )));
+
+        assert_eq!(mismatched["verified"], false);
+        assert_eq!(mismatched["etag"], "abc-2");
+
+        let unverifiable = verification_json(Some(&EtagCheck::Unverifiable(String::from("why"))));
+
+        assert!(unverifiable["verified"].is_null());
+        assert_eq!(unverifiable["reason"], "why");
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+use crate::s3_info::S3ObjectDetails;
+
+// how much of the copy we read at a time when working out its ETag
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The outcome of checking a copy of an S3 object against the ETag of the object.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub enum EtagCheck {
+    // the ETag we computed from the copy is the ETag of the object
+    Matched(String),
+
+    // the ETag we computed from the copy (the second) is not the ETag of the object (the first)
+    Mismatched(String, String),
+
+    // the ETag of the object is not something we can compute - for the given reason
+    Unverifiable(String),
+}
+
+impl EtagCheck {
+    /// Returns true if the copy is known to match the object, false if it is known not
+    /// to, and None if we could not tell.
+    ///
+    pub fn verified(&self) -> Option<bool> {
+        match self {
+            EtagCheck::Matched(_) => Some(true),
+            EtagCheck::Mismatched(_, _) => Some(false),
+            EtagCheck::Unverifiable(_) => None,
+        }
+    }
+}
+
+/// Reads back the copy of the object at the given path and checks it against the ETag
+/// of the object - which S3 makes from the MD5 of the content, or for objects uploaded in
+/// parts, from the MD5s of each of the parts.
+///
+pub fn check_etag(object: &S3ObjectDetails, path: &Path) -> io::Result<EtagCheck> {
+    if !object.etag_is_md5 {
+        return Ok(EtagCheck::Unverifiable(String::from(
+            "the ETag of an object encrypted with SSE-KMS or SSE-C is not an MD5 of its content",
+        )));
+    }
+
+    let etag = object.etag.trim_matches('"').to_ascii_lowercase();
+
+    // an ETag of the form <md5 of the part md5s>-<number of parts>
+    let part_size = match etag.splitn(2, '-').nth(1) {
+        None => None,
+        Some(parts) if parts == object.part_count().to_string() => Some(object.part_size()),
+        Some(_) => {
+            return Ok(EtagCheck::Unverifiable(format!(
+                "the ETag `{}` is not for the {} parts of the object",
+                etag,
+                object.part_count()
+            )))
+        }
+    };
+
+    let computed = compute_etag(File::open(path)?, part_size)?;
+
+    if computed == etag {
+        Ok(EtagCheck::Matched(computed))
+    } else {
+        Ok(EtagCheck::Mismatched(etag, computed))
+    }
+}
+
+/// Returns the ETag S3 would give the content - either a plain MD5 or, if the content was
+/// uploaded in parts of the given size, the MD5 of the MD5s of the parts.
+///
+fn compute_etag<R: Read>(mut content: R, part_size: Option<u64>) -> io::Result<String> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+
+    let mut hasher = Md5::new();
+    let mut part_hashes = Md5::new();
+    let mut parts = 0u32;
+    let mut in_part = 0u64;
+
+    loop {
+        // never read past the end of the current part
+        let wanted = match part_size {
+            Some(size) => (size - in_part).min(buffer.len() as u64) as usize,
+            None => buffer.len(),
+        };
+
+        let read = content.read(&mut buffer[..wanted])?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        in_part += read as u64;
+
+        if part_size == Some(in_part) {
+            part_hashes.update(hasher.finalize_reset());
+            parts += 1;
+            in_part = 0;
+        }
+    }
+
+    match part_size {
+        None => Ok(format!("{:x}", hasher.finalize())),
+        Some(_) => {
+            // the last part is usually short
+            if in_part > 0 {
+                part_hashes.update(hasher.finalize());
+                parts += 1;
+            }
+
+            Ok(format!("{:x}-{}", part_hashes.finalize(), parts))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::s3_etag::compute_etag;
+
+    #[test]
+    fn etags_are_computed_like_s3() {
+        let content = b"hello world";
+
+        assert_eq!(
+            compute_etag(&content[..], None).unwrap(),
+            "5eb63bbbe01eeed093cb22bb8f5acdc3"
+        );
+
+        // uploaded as parts of 4, 4 and 3 bytes
+        assert_eq!(
+            compute_etag(&content[..], Some(4)).unwrap(),
+            "177e85e8bb233bd57a6aabda201a0c2c-3"
+        );
+    }
+}
@@ -1,12 +1,14 @@
-use anyhow::Result;
-use rusoto_core::{HttpClient, Region};
+use anyhow::{anyhow, Result};
+use rusoto_core::{Region, RusotoError};
 use std::io;
 use std::str::FromStr;
 
 use rusoto_credential::{AwsCredentials, StaticProvider};
-use rusoto_s3::{GetBucketLocationRequest, HeadObjectRequest, S3Client, S3};
+use rusoto_s3::{GetBucketLocationRequest, HeadBucketRequest, HeadObjectRequest, S3Client, S3};
 
 use crate::config::Config;
+use crate::proxy::ProxySettings;
+use crate::setup_tls::{create_http_client, TlsCertificates};
 
 #[derive(Debug, Copy, Clone)]
 pub struct S3ObjectBlock {
@@ -17,7 +19,7 @@ pub struct S3ObjectBlock {
     pub part_number: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct S3ObjectDetails {
     // the region of the bucket the object is in
     pub region: Region,
@@ -34,6 +36,10 @@ pub struct S3ObjectDetails {
     // the etag from a HEAD of the entire object
     pub etag: String,
 
+    // whether the etag is made from MD5s of the content - which it is not for objects
+    // encrypted with SSE-KMS or SSE-C
+    pub etag_is_md5: bool,
+
     // the number of the last part of this object, or zero if this object has no parts
     // note that S3 counts part from 1 -> last_part_number(inclusive) so this is not traditional
     // zero indexing in some of our loops
@@ -52,17 +58,40 @@ impl S3ObjectDetails {
         self.last_part_number > 0
     }
 
+    /// Returns the number of parts the object was uploaded in, or zero
+    /// if the object has no parts.
+    ///
+    pub fn part_count(&self) -> u32 {
+        self.last_part_number
+    }
+
+    /// Returns the size of every part but the last, or zero if the object has no parts.
+    ///
+    pub fn part_size(&self) -> u64 {
+        self.part_size_in_bytes
+    }
+
     /// From all the details of the S3 object break the object up into units of work
     /// depending on whether we want to split along S3 part number boundaries or some given
-    /// block size.
+    /// block size. Every block other than the last starts and ends on a multiple of
+    /// alignment (which for direct IO is the block size of the device).
     ///
-    pub fn break_into_blocks(&self, forced_block_size: Option<u64>) -> Vec<S3ObjectBlock> {
+    pub fn break_into_blocks(
+        &self,
+        forced_block_size: Option<u64>,
+        alignment: u64,
+    ) -> Vec<S3ObjectBlock> {
         let mut blocks = vec![];
 
         let mut starter: u64 = 0;
 
-        // the file has parts and we have not been asked to override that
-        if self.has_parts() && forced_block_size.is_none() {
+        let alignment = alignment.max(1);
+
+        // the file has parts (which are aligned) and we have not been asked to override that
+        if self.has_parts()
+            && forced_block_size.is_none()
+            && self.part_size_in_bytes % alignment == 0
+        {
             // note the inclusive range because S3 parts are not zero indexed
             for part_number in 1..=self.last_part_number {
                 blocks.push(S3ObjectBlock {
@@ -84,6 +113,8 @@ impl S3ObjectDetails {
         // the part.. either way we are going to need to chose a block size
         let block_size = forced_block_size.unwrap_or(8 * 1024 * 1024);
 
+        let block_size = ((block_size + alignment - 1) / alignment) * alignment;
+
         let full_blocks_count = self.size_in_bytes / block_size;
         let leftover_bytes = self.size_in_bytes % block_size;
 
@@ -108,27 +139,33 @@ impl S3ObjectDetails {
     }
 }
 
+// the header S3 returns (even on redirects and some errors) telling us where a bucket lives
+const BUCKET_REGION_HEADER: &str = "x-amz-bucket-region";
+
 /// Returns the concrete details of an actual S3 object.
 ///
-/// Uses a couple of API calls, sometimes 3 - given we are going to be downloading
+/// Uses a couple of API calls, sometimes 3 or 4 - given we are going to be downloading
 /// large files, there has not been too much attention paid to optimising this early stage
 /// as it will all be dwarfed by the later transfer..
+/// If the region of the bucket is already known it can be passed in to save
+/// the round trips needed to discover it.
 pub async fn find_s3_object(
     provider: &StaticProvider,
+    certificates: &TlsCertificates,
+    proxy: Option<&ProxySettings>,
     bucket: &str,
     key: &str,
+    known_region: Option<&Region>,
 ) -> Result<S3ObjectDetails, anyhow::Error> {
-    // start by locating the region of the bucket
-    // (there is a possibility of some optimisation here by guessing the bucket and doing
-    //  the first HEAD on the assumption it is in the _current_region.. however
-    //  Rusoto currently has an issue with the 301 redirect we then get - so this is
-    //  probably safer for the moment albeit has an extra round trip)
-    let location_of_bucket =
-        find_s3_bucket_region_using_get_bucket_location(provider, bucket).await?;
+    // start by locating the region of the bucket (if we haven't been told it already)
+    let location_of_bucket = match known_region {
+        Some(region) => region.clone(),
+        None => find_s3_bucket_region(provider, certificates, proxy, bucket).await?,
+    };
 
     // we now make a client in the same region as the bucket
     let s3_client: S3Client = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
+        create_http_client(certificates, proxy).map_err(|e| anyhow!(e))?,
         provider.clone(),
         location_of_bucket.clone(),
     );
@@ -144,6 +181,9 @@ pub async fn find_s3_object(
 
     let full_size = head_full_result.content_length.unwrap() as u64;
 
+    let etag_is_md5 = head_full_result.server_side_encryption.as_deref() != Some("aws:kms")
+        && head_full_result.sse_customer_algorithm.is_none();
+
     // println!("{:?}", head_full_result);
 
     // then a head asking for the first part
@@ -157,19 +197,22 @@ pub async fn find_s3_object(
     let head_part_result = s3_client.head_object(head_part_request).await?;
 
     if head_part_result.parts_count.is_some() {
-        let from_parts =
-            head_part_result.content_length.unwrap() * head_part_result.parts_count.unwrap();
-        let leftover = from_parts - full_size as i64;
+        let part_size = head_part_result.content_length.unwrap() as u64;
+        let parts = head_part_result.parts_count.unwrap() as u64;
+
+        // every part but the last is the same size
+        let last_part_size = full_size - (parts - 1) * part_size;
 
         Ok(S3ObjectDetails {
             region: location_of_bucket,
             bucket: bucket.to_string(),
             key: key.to_string(),
             etag: head_full_result.e_tag.unwrap(),
+            etag_is_md5,
             size_in_bytes: full_size,
-            last_part_number: head_part_result.parts_count.unwrap() as u32,
-            part_size_in_bytes: head_part_result.content_length.unwrap() as u64,
-            last_part_size_in_bytes: leftover as u64,
+            last_part_number: parts as u32,
+            part_size_in_bytes: part_size,
+            last_part_size_in_bytes: last_part_size,
         })
     } else {
         Ok(S3ObjectDetails {
@@ -177,6 +220,7 @@ pub async fn find_s3_object(
             bucket: bucket.to_string(),
             key: key.to_string(),
             etag: head_full_result.e_tag.unwrap(),
+            etag_is_md5,
             size_in_bytes: full_size,
             // this file does not have parts so fetching by parts is not available to us
             last_part_number: 0,
@@ -186,6 +230,66 @@ pub async fn find_s3_object(
     }
 }
 
+/// Find the location of a bucket by trying the techniques available to us in
+/// order of least permissions required.
+///
+async fn find_s3_bucket_region(
+    provider: &StaticProvider,
+    certificates: &TlsCertificates,
+    proxy: Option<&ProxySettings>,
+    bucket: &str,
+) -> anyhow::Result<Region, anyhow::Error> {
+    match find_s3_bucket_region_using_head_bucket(provider, certificates, proxy, bucket).await {
+        Ok(region) => Ok(region),
+        // GetBucketLocation is our last resort as it is unlikely to work cross account
+        Err(_) => {
+            find_s3_bucket_region_using_get_bucket_location(provider, certificates, proxy, bucket)
+                .await
+        }
+    }
+}
+
+/// Find the location of a bucket by doing a HEAD of the bucket against
+/// the us-east-1 endpoint. If the bucket is elsewhere then S3 responds
+/// with a 301 redirect - which Rusoto reports as an unknown error - but which
+/// includes the real region of the bucket in a header. The same header is
+/// also returned in 403 responses, so this works even when we are only allowed
+/// to read objects in the bucket.
+///
+async fn find_s3_bucket_region_using_head_bucket(
+    provider: &StaticProvider,
+    certificates: &TlsCertificates,
+    proxy: Option<&ProxySettings>,
+    bucket: &str,
+) -> anyhow::Result<Region, anyhow::Error> {
+    let s3_client: S3Client = S3Client::new_with(
+        create_http_client(certificates, proxy).map_err(|e| anyhow!(e))?,
+        provider.clone(),
+        Region::UsEast1,
+    );
+
+    let head_result = s3_client
+        .head_bucket(HeadBucketRequest {
+            bucket: bucket.to_string(),
+            ..Default::default()
+        })
+        .await;
+
+    match head_result {
+        // no redirect means we asked the right region first time
+        Ok(_) => Ok(Region::UsEast1),
+        Err(RusotoError::Unknown(response)) => match response.headers.get(BUCKET_REGION_HEADER) {
+            Some(region_name) => Ok(Region::from_str(region_name.as_str())?),
+            None => Err(anyhow!(
+                "HEAD of bucket returned HTTP status {} without a `{}` header",
+                response.status,
+                BUCKET_REGION_HEADER
+            )),
+        },
+        Err(e) => Err(anyhow::Error::new(e)),
+    }
+}
+
 /// Find the location of a bucket using the AWS API for this purpose.
 /// Unfortunately, the AWS API call requires extra permissions over that
 /// which is necessary for a plain GetObject - so this is not ideal.
@@ -196,11 +300,13 @@ pub async fn find_s3_object(
 ///
 async fn find_s3_bucket_region_using_get_bucket_location(
     provider: &StaticProvider,
+    certificates: &TlsCertificates,
+    proxy: Option<&ProxySettings>,
     bucket: &str,
 ) -> anyhow::Result<Region, anyhow::Error> {
     // a client in any region can determine a bucket location
     let s3_client: S3Client = S3Client::new_with(
-        HttpClient::new().expect("failed to create request dispatcher"),
+        create_http_client(certificates, proxy).map_err(|e| anyhow!(e))?,
         provider.clone(),
         Region::default(),
     );
@@ -272,3 +378,48 @@ HeadObjectOutput {
     website_redirect_location: None,
 }
  */
+
+#[cfg(test)]
+mod tests {
+    use rusoto_core::Region;
+
+    use crate::s3_info::S3ObjectDetails;
+
+    fn object(size_in_bytes: u64, part_size_in_bytes: u64) -> S3ObjectDetails {
+        let parts = (size_in_bytes + part_size_in_bytes - 1) / part_size_in_bytes;
+
+        S3ObjectDetails {
+            region: Region::UsEast1,
+            bucket: String::from("bucket"),
+            key: String::from("key"),
+            size_in_bytes,
+            etag: String::new(),
+            etag_is_md5: true,
+            last_part_number: parts as u32,
+            part_size_in_bytes,
+            last_part_size_in_bytes: size_in_bytes - (parts - 1) * part_size_in_bytes,
+        }
+    }
+
+    #[test]
+    fn blocks_are_aligned() {
+        // aligned parts are used as they are
+        let blocks = object(20_000_000, 8 * 1024 * 1024).break_into_blocks(None, 4096);
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].part_number, 3);
+        assert_eq!(blocks[2].length, 20_000_000 - 16 * 1024 * 1024);
+
+        // whereas unaligned parts are replaced by aligned ranges
+        let blocks = object(20_000_000, 5_000_000).break_into_blocks(None, 4096);
+
+        assert!(blocks.iter().all(|b| b.part_number == 0));
+        assert!(blocks.iter().all(|b| b.start % 4096 == 0));
+        assert_eq!(blocks.iter().map(|b| b.length).sum::<u64>(), 20_000_000);
+
+        let blocks = object(20_000_000, 5_000_000).break_into_blocks(Some(1_000_000), 4096);
+
+        assert_eq!(blocks[0].length, 1_003_520);
+        assert_eq!(blocks[1].start, 1_003_520);
+    }
+}
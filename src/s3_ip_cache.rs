@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusoto_core::Region;
+use serde_yaml::{Mapping, Value};
+
+use crate::s3_ip_pool::{IpVersion, S3IpStats};
+
+// the file we cache endpoints in when the user hasn't explicitly given us one
+const DEFAULT_CACHE_FILE: &str = ".cache/s3bfg/endpoints.yaml";
+
+const UPDATED_KEY: &str = "updated";
+const IPS_KEY: &str = "ips";
+const BLOCKS_KEY: &str = "blocks";
+const ERRORS_KEY: &str = "errors";
+
+/// A YAML file of the S3 endpoints (IP addresses) we have previously discovered,
+/// along with how healthy they were when we last used them. Endpoints are cached
+/// separately for each region (and IP version) that we discover them for.
+///
+/// Caching is best effort - a missing or corrupt cache file is treated as empty.
+///
+pub struct S3IpCache {
+    path: PathBuf,
+
+    entries: Mapping,
+}
+
+impl S3IpCache {
+    /// Returns the location of the cache if the user hasn't given one - being
+    /// `~/.cache/s3bfg/endpoints.yaml`.
+    ///
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(DEFAULT_CACHE_FILE))
+    }
+
+    /// Loads the cache from the given path.
+    ///
+    pub fn load(path: &Path) -> S3IpCache {
+        let entries = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_yaml::from_str::<Value>(content.as_str()).ok())
+            .and_then(|value| match value {
+                Value::Mapping(m) => Some(m),
+                _ => None,
+            })
+            .unwrap_or_else(Mapping::new);
+
+        S3IpCache {
+            path: path.to_path_buf(),
+            entries,
+        }
+    }
+
+    /// Returns the healthy endpoints cached under the given key, or nothing if
+    /// the entry is older than the ttl.
+    ///
+    pub fn fresh_ips(&self, key: &str, ttl: Duration) -> Vec<IpAddr> {
+        let entry = match self.entries.get(&Value::from(key)) {
+            Some(Value::Mapping(m)) => m,
+            _ => return vec![],
+        };
+
+        let updated = entry
+            .get(&Value::from(UPDATED_KEY))
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+
+        if now_secs().saturating_sub(updated) > ttl.as_secs() {
+            return vec![];
+        }
+
+        let ips = match entry.get(&Value::from(IPS_KEY)) {
+            Some(Value::Mapping(m)) => m,
+            _ => return vec![],
+        };
+
+        ips.iter()
+            .filter(|(_, health)| is_healthy(health))
+            .filter_map(|(ip, _)| ip.as_str().and_then(|s| s.parse::<IpAddr>().ok()))
+            .collect()
+    }
+
+    /// Replaces the entry under the given key with the given endpoints - where any
+    /// endpoint we haven't used in this run keeps the health it had previously.
+    ///
+    pub fn update(&mut self, key: &str, endpoints: &BTreeMap<String, S3IpStats>) {
+        let previous_ips = match self.entries.get(&Value::from(key)) {
+            Some(Value::Mapping(entry)) => match entry.get(&Value::from(IPS_KEY)) {
+                Some(Value::Mapping(m)) => m.clone(),
+                _ => Mapping::new(),
+            },
+            _ => Mapping::new(),
+        };
+
+        let mut ips = Mapping::new();
+
+        for (ip, stats) in endpoints.iter() {
+            let health = match previous_ips.get(&Value::from(ip.as_str())) {
+                Some(previous) if stats.blocks == 0 && stats.errors == 0 => previous.clone(),
+                _ => {
+                    let mut h = Mapping::new();
+                    h.insert(Value::from(BLOCKS_KEY), Value::from(stats.blocks as u64));
+                    h.insert(Value::from(ERRORS_KEY), Value::from(stats.errors as u64));
+                    Value::Mapping(h)
+                }
+            };
+
+            ips.insert(Value::from(ip.as_str()), health);
+        }
+
+        let mut entry = Mapping::new();
+        entry.insert(Value::from(UPDATED_KEY), Value::from(now_secs()));
+        entry.insert(Value::from(IPS_KEY), Value::Mapping(ips));
+
+        self.entries.insert(Value::from(key), Value::Mapping(entry));
+    }
+
+    /// Writes the cache back to disk - via a temporary file and rename so that
+    /// concurrent runs never see a partial file.
+    ///
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_yaml::to_string(&self.entries)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let mut temp_path = self.path.clone();
+        temp_path.set_extension(format!("yaml.{}.tmp", std::process::id()));
+
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+/// Returns the key we cache endpoints under - as the endpoints differ for each region,
+/// IP version and whether they are dualstack.
+///
+pub fn cache_key(region: &Region, ip_version: IpVersion, dualstack: bool) -> String {
+    let version = match ip_version {
+        IpVersion::V4 => "v4",
+        IpVersion::V6 => "v6",
+        IpVersion::Both => "both",
+    };
+
+    if dualstack {
+        format!("{}/{}/dualstack", region.name(), version)
+    } else {
+        format!("{}/{}", region.name(), version)
+    }
+}
+
+/// An endpoint is healthy unless when last used it failed at least as many blocks
+/// as it succeeded.
+///
+fn is_healthy(health: &Value) -> bool {
+    let count = |k: &str| health.get(k).and_then(Value::as_u64).unwrap_or(0);
+
+    let errors = count(ERRORS_KEY);
+
+    errors == 0 || errors < count(BLOCKS_KEY)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use rusoto_core::Region;
+    use tempfile::tempdir;
+
+    use crate::s3_ip_cache::{cache_key, S3IpCache};
+    use crate::s3_ip_pool::{IpVersion, S3IpStats};
+
+    #[test]
+    fn healthy_ips_survive_a_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("nested").join("endpoints.yaml");
+
+        let key = cache_key(&Region::UsWest2, IpVersion::V4, false);
+        assert_eq!(key, "us-west-2/v4");
+
+        let mut good = S3IpStats::new();
+        good.blocks = 10;
+        good.errors = 1;
+
+        let mut bad = S3IpStats::new();
+        bad.blocks = 1;
+        bad.errors = 3;
+
+        let mut endpoints = BTreeMap::new();
+        endpoints.insert("52.218.1.1".to_string(), good);
+        endpoints.insert("52.218.2.2".to_string(), bad);
+        endpoints.insert("52.218.3.3".to_string(), S3IpStats::new());
+
+        let mut cache = S3IpCache::load(&path);
+        assert!(cache.fresh_ips(key.as_str(), Duration::from_secs(60)).is_empty());
+
+        cache.update(key.as_str(), &endpoints);
+        cache.save().unwrap();
+
+        let reloaded = S3IpCache::load(&path);
+
+        let ips = reloaded.fresh_ips(key.as_str(), Duration::from_secs(60));
+
+        assert_eq!(
+            ips,
+            vec![
+                "52.218.1.1".parse::<IpAddr>().unwrap(),
+                "52.218.3.3".parse::<IpAddr>().unwrap()
+            ]
+        );
+
+        // other regions are not affected
+        assert!(reloaded
+            .fresh_ips("us-east-1/v4", Duration::from_secs(60))
+            .is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use rand::{thread_rng, Rng};
+
+use crate::s3_ip_pool::S3IpStats;
+
+/// A way of choosing which S3 endpoint (IP address) the next block in a slot should use.
+///
+pub trait IpSelectionPolicy: Send + Sync {
+    /// Returns the index of the endpoint to use out of the given candidates (which
+    /// will never be empty).
+    ///
+    fn choose(&self, slot: usize, candidates: &[(&String, &S3IpStats)]) -> usize;
+}
+
+/// The selection policies that can be chosen by the user.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpPolicy {
+    // the endpoint we have chosen the least
+    LeastUsed,
+
+    // the least used of the half of the endpoints with the fastest probed connection times
+    Fastest,
+
+    // a random endpoint, weighted by the throughput we have seen from it
+    Weighted,
+
+    // the endpoint the slot used last time (for as long as it is available)
+    Sticky,
+}
+
+impl IpPolicy {
+    /// Returns a new instance of the policy.
+    ///
+    pub fn create(&self) -> Box<dyn IpSelectionPolicy> {
+        match self {
+            IpPolicy::LeastUsed => Box::new(LeastUsedPolicy),
+            IpPolicy::Fastest => Box::new(FastestPolicy),
+            IpPolicy::Weighted => Box::new(WeightedPolicy),
+            IpPolicy::Sticky => Box::new(StickyPolicy::new()),
+        }
+    }
+
+    /// Returns true if the policy makes use of the connection times we measure
+    /// by probing the endpoints.
+    ///
+    pub fn needs_probe(&self) -> bool {
+        match self {
+            IpPolicy::Fastest | IpPolicy::Weighted => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpPolicy::LeastUsed => write!(f, "least-used"),
+            IpPolicy::Fastest => write!(f, "fastest"),
+            IpPolicy::Weighted => write!(f, "weighted"),
+            IpPolicy::Sticky => write!(f, "sticky"),
+        }
+    }
+}
+
+impl FromStr for IpPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "least-used" => Ok(IpPolicy::LeastUsed),
+            "fastest" => Ok(IpPolicy::Fastest),
+            "weighted" => Ok(IpPolicy::Weighted),
+            "sticky" => Ok(IpPolicy::Sticky),
+            _ => Err(format!("Unknown IP selection policy `{}`", s)),
+        }
+    }
+}
+
+pub struct LeastUsedPolicy;
+
+impl IpSelectionPolicy for LeastUsedPolicy {
+    fn choose(&self, _slot: usize, candidates: &[(&String, &S3IpStats)]) -> usize {
+        least_used(candidates, |_| true)
+    }
+}
+
+pub struct FastestPolicy;
+
+impl IpSelectionPolicy for FastestPolicy {
+    fn choose(&self, _slot: usize, candidates: &[(&String, &S3IpStats)]) -> usize {
+        let mut rtts: Vec<_> = candidates
+            .iter()
+            .filter_map(|(_, stats)| stats.probe_rtt)
+            .collect();
+
+        // with nothing probed we have nothing to go on
+        if rtts.is_empty() {
+            return least_used(candidates, |_| true);
+        }
+
+        rtts.sort();
+
+        // the slowest connection time that still counts as fast
+        let cutoff = rtts[(rtts.len() - 1) / 2];
+
+        least_used(candidates, |stats| {
+            stats.probe_rtt.map_or(false, |rtt| rtt <= cutoff)
+        })
+    }
+}
+
+pub struct WeightedPolicy;
+
+impl IpSelectionPolicy for WeightedPolicy {
+    fn choose(&self, _slot: usize, candidates: &[(&String, &S3IpStats)]) -> usize {
+        let weights = weights(candidates);
+
+        let total: f64 = weights.iter().sum();
+
+        pick(&weights, thread_rng().gen_range(0.0, total))
+    }
+}
+
+/// Returns the weight of each candidate - being the throughput we have seen from it or
+/// failing that, how quickly we could connect to it. As those are measured in different
+/// units each is taken relative to the average of the candidates that have it (so an
+/// average endpoint weighs 1.0 either way), and those we know nothing about get that
+/// average weight so that they get a go.
+///
+fn weights(candidates: &[(&String, &S3IpStats)]) -> Vec<f64> {
+    let throughputs = relative_to_average(
+        candidates
+            .iter()
+            .map(|(_, stats)| stats.throughput_mean().filter(|t| *t > 0.0))
+            .collect(),
+    );
+
+    let connect_speeds = relative_to_average(
+        candidates
+            .iter()
+            .map(|(_, stats)| {
+                stats
+                    .probe_rtt
+                    .map(|rtt| 1.0 / rtt.as_secs_f64().max(0.000_001))
+            })
+            .collect(),
+    );
+
+    throughputs
+        .iter()
+        .zip(connect_speeds.iter())
+        .map(|(throughput, speed)| throughput.or(*speed).unwrap_or(1.0))
+        .collect()
+}
+
+/// Returns each of the measurements divided by the average of them all (ignoring
+/// those that are missing).
+///
+fn relative_to_average(measured: Vec<Option<f64>>) -> Vec<Option<f64>> {
+    let known: Vec<f64> = measured.iter().filter_map(|m| *m).collect();
+
+    if known.is_empty() {
+        return measured;
+    }
+
+    let average = known.iter().sum::<f64>() / known.len() as f64;
+
+    measured.iter().map(|m| m.map(|m| m / average)).collect()
+}
+
+/// Returns the index of the weight that the point (from 0 up to the total of the weights)
+/// falls within - where each weight covers a stretch of that range as long as itself.
+///
+fn pick(weights: &[f64], mut point: f64) -> usize {
+    for (i, w) in weights.iter().enumerate() {
+        if point < *w {
+            return i;
+        }
+        point -= w;
+    }
+
+    weights.len() - 1
+}
+
+pub struct StickyPolicy {
+    // the endpoint each slot last used
+    last: Mutex<HashMap<usize, String>>,
+}
+
+impl StickyPolicy {
+    pub fn new() -> StickyPolicy {
+        StickyPolicy {
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl IpSelectionPolicy for StickyPolicy {
+    fn choose(&self, slot: usize, candidates: &[(&String, &S3IpStats)]) -> usize {
+        let mut last = self.last.lock().unwrap();
+
+        let chosen = last
+            .get(&slot)
+            .and_then(|ip| candidates.iter().position(|(c, _)| *c == ip))
+            .unwrap_or_else(|| least_used(candidates, |_| true));
+
+        last.insert(slot, candidates[chosen].0.clone());
+
+        chosen
+    }
+}
+
+/// Returns the index of the least used candidate that matches the filter (or of all
+/// the candidates if none match).
+///
+fn least_used<F: Fn(&S3IpStats) -> bool>(candidates: &[(&String, &S3IpStats)], filter: F) -> usize {
+    let any_match = candidates.iter().any(|(_, stats)| filter(stats));
+
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, stats))| !any_match || filter(stats))
+        .min_by_key(|(_, (_, stats))| stats.usage)
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::s3_ip_policy::{pick, weights, IpPolicy};
+    use crate::s3_ip_pool::S3IpStats;
+
+    fn endpoint(usage: u32, rtt_millis: Option<u64>) -> S3IpStats {
+        let mut stats = S3IpStats::new();
+        stats.usage = usage;
+        stats.probe_rtt = rtt_millis.map(Duration::from_millis);
+        stats
+    }
+
+    #[test]
+    fn policies_choose_as_expected() {
+        let names: Vec<String> = (1..=4).map(|i| format!("52.216.0.{}", i)).collect();
+
+        let stats = vec![
+            endpoint(3, Some(5)),
+            endpoint(1, Some(50)),
+            endpoint(2, Some(4)),
+            endpoint(5, None),
+        ];
+
+        let candidates: Vec<_> = names.iter().zip(stats.iter()).collect();
+
+        assert_eq!(IpPolicy::LeastUsed.create().choose(0, &candidates), 1);
+
+        // the fastest two are 0 and 2 - of which 2 is less used
+        assert_eq!(IpPolicy::Fastest.create().choose(0, &candidates), 2);
+
+        let sticky = IpPolicy::Sticky.create();
+        assert_eq!(sticky.choose(7, &candidates), 1);
+
+        // even if something else is now less used, the slot sticks with what it had
+        let stats = vec![endpoint(0, None), endpoint(9, None)];
+        let candidates: Vec<_> = names.iter().zip(stats.iter()).collect();
+        assert_eq!(sticky.choose(7, &candidates), 1);
+        assert_eq!(sticky.choose(8, &candidates), 0);
+    }
+
+    #[test]
+    fn weighted_choices_favour_throughput() {
+        let names: Vec<String> = (1..=3).map(|i| format!("52.216.0.{}", i)).collect();
+
+        let mut fast = endpoint(0, None);
+        fast.throughput.record(3_000_000).unwrap();
+
+        let mut slow = endpoint(0, None);
+        slow.throughput.record(1_000_000).unwrap();
+
+        // an endpoint we know nothing about gets the average weight
+        let unknown = endpoint(0, None);
+
+        let stats = vec![fast, slow, unknown];
+        let candidates: Vec<_> = names.iter().zip(stats.iter()).collect();
+
+        let weights = weights(&candidates);
+
+        // histograms are only accurate to 3 significant figures
+        assert!((weights[0] / weights[1] - 3.0).abs() < 0.01);
+        assert!((weights[2] / weights[1] - 2.0).abs() < 0.01);
+
+        // so the fast endpoint covers half of the range we choose from
+        let total: f64 = weights.iter().sum();
+
+        assert_eq!(pick(&weights, 0.0), 0);
+        assert_eq!(pick(&weights, total * 0.49), 0);
+        assert_eq!(pick(&weights, total * 0.51), 1);
+        assert_eq!(pick(&weights, total * 0.67), 2);
+        assert_eq!(pick(&weights, total), 2);
+    }
+
+    #[test]
+    fn endpoints_that_were_only_probed_still_get_a_go() {
+        let names: Vec<String> = (1..=3).map(|i| format!("52.216.0.{}", i)).collect();
+
+        let mut used = endpoint(0, Some(10));
+        used.throughput.record(100_000_000).unwrap();
+
+        let stats = vec![used, endpoint(0, Some(5)), endpoint(0, Some(20))];
+        let candidates: Vec<_> = names.iter().zip(stats.iter()).collect();
+
+        let weights = weights(&candidates);
+
+        // connection times are weighed against those of all the probed endpoints (5, 10
+        // and 20ms) and not against throughput
+        assert!((weights[0] - 1.0).abs() < 0.01);
+        assert!((weights[1] - 12.0 / 7.0).abs() < 0.01);
+        assert!((weights[2] - 3.0 / 7.0).abs() < 0.01);
+    }
+}
@@ -1,62 +1,399 @@
 use std::collections::BTreeMap;
 use std::iter;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Mul;
+use std::str::FromStr;
 use std::sync::Mutex;
-use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::future::join_all;
+use hdrhistogram::Histogram;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use rusoto_core::Region;
-use tokio::net::UdpSocket;
 use tokio::prelude::*;
-use trust_dns_client::client::{AsyncClient, ClientHandle};
-use trust_dns_client::op::DnsResponse;
-use trust_dns_client::rr::{DNSClass, Name, RData, Record, RecordType};
-use trust_dns_client::udp::UdpClientStream;
+use tokio::time::delay_for;
+use trust_dns_client::rr::RecordType;
+
+use crate::download_block::BlockResult;
+use crate::s3_ip_dns::DnsStrategy;
+use crate::s3_ip_policy::{IpPolicy, IpSelectionPolicy};
+
+// the number of failed blocks before we consider retiring an endpoint
+const UNHEALTHY_ERRORS: u32 = 3;
+
+/// The IP protocol versions of the S3 endpoints we will discover and connect to.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpVersion {
+    // only IPv4 endpoints (A records)
+    V4,
+
+    // only IPv6 endpoints (AAAA records) - which requires the dualstack S3 endpoints
+    V6,
+
+    // a mix of IPv4 and IPv6 endpoints - which requires the dualstack S3 endpoints
+    Both,
+}
+
+impl IpVersion {
+    /// Returns the DNS record types we need to ask for to find endpoints of this version.
+    ///
+    pub fn record_types(&self) -> Vec<RecordType> {
+        match self {
+            IpVersion::V4 => vec![RecordType::A],
+            IpVersion::V6 => vec![RecordType::AAAA],
+            IpVersion::Both => vec![RecordType::A, RecordType::AAAA],
+        }
+    }
+
+    /// Returns true if endpoints of this version are only available via the
+    /// dualstack S3 hostnames.
+    ///
+    pub fn requires_dualstack(&self) -> bool {
+        *self != IpVersion::V4
+    }
+}
+
+impl FromStr for IpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "v4" | "4" | "ipv4" => Ok(IpVersion::V4),
+            "v6" | "6" | "ipv6" => Ok(IpVersion::V6),
+            "both" => Ok(IpVersion::Both),
+            _ => Err(format!("Unknown IP version `{}`", s)),
+        }
+    }
+}
+
+/// What we know about a single S3 endpoint (IP address) - how often we have
+/// chosen it and how well it has performed when we did.
+///
+#[derive(Clone, Debug)]
+pub struct S3IpStats {
+    // the count of the number of times we have chosen this endpoint for a block
+    pub usage: u32,
+
+    // the blocks (and bytes in them) we successfully downloaded from this endpoint
+    pub blocks: u32,
+    pub bytes: u64,
+
+    // the count of blocks that failed whilst using this endpoint
+    pub errors: u32,
+
+    // per block throughput in bytes/sec
+    pub throughput: Histogram<u64>,
+
+    // per block TLS handshake duration in nanoseconds
+    pub tls_setup: Histogram<u64>,
+
+    // when the DNS record we found this endpoint in expires (if ever)
+    pub expires: Option<Instant>,
+
+    // if true we no longer choose this endpoint (it has expired or performed badly)
+    pub retired: bool,
+
+    // how long it took to connect (TCP) when we probed this endpoint - which is what
+    // we rank endpoints on
+    pub probe_rtt: Option<Duration>,
+
+    // how long the TLS handshake then took when we probed this endpoint (if we talk TLS)
+    pub probe_tls: Option<Duration>,
+}
+
+impl S3IpStats {
+    pub fn new() -> S3IpStats {
+        S3IpStats {
+            usage: 0,
+            blocks: 0,
+            bytes: 0,
+            errors: 0,
+            throughput: Histogram::<u64>::new(3).expect("failed to create histogram"),
+            tls_setup: Histogram::<u64>::new(3).expect("failed to create histogram"),
+            expires: None,
+            retired: false,
+            probe_rtt: None,
+            probe_tls: None,
+        }
+    }
+
+    /// Returns true if the DNS record we found this endpoint in has expired.
+    ///
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires.map_or(false, |e| e <= now)
+    }
+
+    /// Returns true if this endpoint has failed enough blocks that we should
+    /// stop using it.
+    ///
+    pub fn is_unhealthy(&self) -> bool {
+        self.errors >= UNHEALTHY_ERRORS && self.errors > self.blocks
+    }
+
+    /// Returns true if we can choose this endpoint for new blocks.
+    ///
+    pub fn is_available(&self, now: Instant) -> bool {
+        !self.retired && !self.is_expired(now)
+    }
+
+    /// Returns the throughput (in bytes/sec) at the given quantile of all the blocks
+    /// downloaded from this endpoint, or None if there have been none.
+    ///
+    pub fn throughput_at_quantile(&self, quantile: f64) -> Option<u64> {
+        if self.throughput.len() == 0 {
+            None
+        } else {
+            Some(self.throughput.value_at_quantile(quantile))
+        }
+    }
+
+    /// Returns the mean throughput (in bytes/sec) of all the blocks downloaded from
+    /// this endpoint, or None if there have been none.
+    ///
+    pub fn throughput_mean(&self) -> Option<f64> {
+        if self.throughput.len() == 0 {
+            None
+        } else {
+            Some(self.throughput.mean())
+        }
+    }
+
+    /// Returns the mean TLS handshake duration, or None if there have been no blocks.
+    ///
+    pub fn tls_setup_mean(&self) -> Option<Duration> {
+        if self.tls_setup.len() == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos(self.tls_setup.mean() as u64))
+        }
+    }
+}
 
 /// A thread-safe data structure for pooling distinct S3 endpoints (IP addresses)
-/// and recording the usage of them.
+/// and recording the usage and performance of them.
 ///
 pub struct S3IpPool {
     // a map of IP addresses that have been identified as active S3 servers, and
-    // the count of the number of times we have chosen them for use
-    pub ips: Mutex<BTreeMap<String, u32>>,
+    // the statistics of our use of them
+    pub ips: Mutex<BTreeMap<String, S3IpStats>>,
+
+    // how we choose between the endpoints
+    policy: Box<dyn IpSelectionPolicy>,
+
+    // why each block that failed (on whichever endpoint) did so
+    block_errors: Mutex<Vec<String>>,
 }
 
 impl S3IpPool {
     /// Returns a new thread-safe S3 IP address pool, initially populated with
-    /// no endpoints.
+    /// no endpoints, that chooses the least used endpoint.
     pub fn new() -> S3IpPool {
         S3IpPool {
             ips: Mutex::new(BTreeMap::new()),
+            policy: IpPolicy::LeastUsed.create(),
+            block_errors: Mutex::new(vec![]),
         }
     }
 
+    /// Returns the pool changed to choose endpoints using the given policy.
+    ///
+    pub fn with_policy(mut self, policy: Box<dyn IpSelectionPolicy>) -> S3IpPool {
+        self.policy = policy;
+        self
+    }
+
     /// Returns the count of S3 IP addresses currently in our pool.
     ///
     pub fn ip_count(&self) -> u16 {
         return self.ips.lock().unwrap().len() as u16;
     }
 
-    /// Returns an IP address from our pool that has been used the least, and the
-    /// current count for that IP address.
+    /// Returns the count of S3 IP addresses in our pool that we can still choose.
     ///
-    pub fn use_least_used_ip(&self) -> (Ipv4Addr, u32) {
+    pub fn active_ip_count(&self) -> u16 {
+        let now = Instant::now();
+
+        self.ips
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stats| stats.is_available(now))
+            .count() as u16
+    }
+
+    /// Returns an IP address from our pool, as chosen by our policy, for the next block
+    /// in the given slot - and the count of the times we have now chosen that IP address.
+    ///
+    pub fn choose_ip(&self, slot: usize) -> (IpAddr, u32) {
+        self.choose_ip_avoiding(slot, None)
+    }
+
+    /// Returns an IP address as per `choose_ip` - but not the one given (say because a
+    /// block just failed using it) unless it is the only one we can choose.
+    ///
+    pub fn choose_ip_avoiding(&self, slot: usize, avoid: Option<&IpAddr>) -> (IpAddr, u32) {
         let mut ips_unmutex = self.ips.lock().unwrap();
 
-        // our S3 endpoint with the lowest usage so far
-        let lowest_usage = ips_unmutex.iter_mut().min_by_key(|x| *x.1);
+        let now = Instant::now();
+
+        // we only fall back to expired or retired endpoints if there is nothing else
+        let none_available = !ips_unmutex.values().any(|stats| stats.is_available(now));
+
+        let chosen_ip = {
+            let mut candidates: Vec<(&String, &S3IpStats)> = ips_unmutex
+                .iter()
+                .filter(|x| none_available || x.1.is_available(now))
+                .collect();
+
+            if let Some(avoid) = avoid.map(|ip| ip.to_string()) {
+                if candidates.iter().any(|(ip, _)| **ip != avoid) {
+                    candidates.retain(|(ip, _)| **ip != avoid);
+                }
+            }
+
+            candidates[self.policy.choose(slot, &candidates)].0.clone()
+        };
 
         // access the whole entry
-        let (ip, count) = lowest_usage.unwrap();
+        let stats = ips_unmutex.get_mut(&chosen_ip).unwrap();
 
         // bump the count
-        *count += 1;
+        stats.usage += 1;
 
-        return (ip.parse::<Ipv4Addr>().unwrap(), *count);
+        return (chosen_ip.parse::<IpAddr>().unwrap(), stats.usage);
+    }
+
+    /// Returns the IP addresses in our pool that are still available to choose but have
+    /// never been probed.
+    ///
+    pub fn unprobed_ips(&self) -> Vec<IpAddr> {
+        let now = Instant::now();
+
+        self.ips
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, stats)| stats.is_available(now) && stats.probe_rtt.is_none())
+            .filter_map(|(ip, _)| ip.parse::<IpAddr>().ok())
+            .collect()
+    }
+
+    /// Records the outcome of probing an endpoint (its connect time and then any TLS
+    /// handshake time) - with a failed probe counting as an error against it.
+    ///
+    pub fn record_probe(&self, ip: &IpAddr, outcome: Option<(Duration, Option<Duration>)>) {
+        let mut ips_unmutex = self.ips.lock().unwrap();
+
+        if let Some(stats) = ips_unmutex.get_mut(&ip.to_string()) {
+            match outcome {
+                Some((connect, tls)) => {
+                    stats.probe_rtt = Some(connect);
+                    stats.probe_tls = tls;
+                }
+                None => stats.errors += 1,
+            }
+        }
+    }
+
+    /// Adds the given IP addresses (for instance from a cache, or given to us by the user)
+    /// to the pool and returns the number of entries we ended up with.
+    ///
+    pub fn add_ips(&self, new_ips: &[IpAddr]) -> u16 {
+        let mut ips_unmutex = self.ips.lock().unwrap();
+
+        for ip in new_ips {
+            ips_unmutex
+                .entry(ip.to_string())
+                .or_insert_with(S3IpStats::new);
+        }
+
+        ips_unmutex.len() as u16
+    }
+
+    /// Retires any endpoints whose DNS records have expired or that have performed
+    /// badly - though never our last available endpoint. Returns the number retired.
+    ///
+    pub fn retire_ips(&self) -> usize {
+        let mut ips_unmutex = self.ips.lock().unwrap();
+
+        let now = Instant::now();
+
+        let mut available = ips_unmutex
+            .values()
+            .filter(|stats| stats.is_available(now))
+            .count();
+
+        let mut retired = 0;
+
+        // retire the unhealthy before the merely expired
+        let mut candidates: Vec<(&String, &mut S3IpStats)> = ips_unmutex
+            .iter_mut()
+            .filter(|(_, stats)| !stats.retired && (stats.is_unhealthy() || stats.is_expired(now)))
+            .collect();
+
+        candidates.sort_by_key(|(_, stats)| !stats.is_unhealthy());
+
+        for (_, stats) in candidates {
+            // an expired endpoint is not counted as available anyway
+            if stats.is_available(now) {
+                if available <= 1 {
+                    break;
+                }
+                available -= 1;
+            }
+
+            stats.retired = true;
+            retired += 1;
+        }
+
+        retired
+    }
+
+    /// Records the successful download of a block from one of our S3 endpoints.
+    ///
+    pub fn record_block(&self, result: &BlockResult) {
+        let mut ips_unmutex = self.ips.lock().unwrap();
+
+        if let Some(stats) = ips_unmutex.get_mut(&result.s3_socket_addr.ip().to_string()) {
+            stats.blocks += 1;
+            stats.bytes += result.bytes;
+
+            // histograms cannot hold a 0 value so we clamp to 1 (and recording into an
+            // auto resizing histogram only fails for values beyond its largest)
+            stats
+                .throughput
+                .record(result.bytes_per_sec().max(1))
+                .expect("failed to observe histogram value");
+            stats
+                .tls_setup
+                .record(result.tls_setup.max(1))
+                .expect("failed to observe histogram value");
+        }
+    }
+
+    /// Records that a block failed whilst using the given S3 endpoint.
+    ///
+    pub fn record_error(&self, s3_socket_addr: &SocketAddr, error: &anyhow::Error) {
+        {
+            let mut ips_unmutex = self.ips.lock().unwrap();
+
+            if let Some(stats) = ips_unmutex.get_mut(&s3_socket_addr.ip().to_string()) {
+                stats.errors += 1;
+            }
+        }
+
+        self.block_errors
+            .lock()
+            .unwrap()
+            .push(format!("{} - {:#}", s3_socket_addr, error));
+    }
+
+    /// Returns why each block that failed did so, in the order they failed.
+    ///
+    pub fn block_errors(&self) -> Vec<String> {
+        self.block_errors.lock().unwrap().clone()
     }
 
     /// Populates the pool with entries we fetch in parallel from a DNS server and
@@ -65,22 +402,31 @@ impl S3IpPool {
     pub async fn populate_ips(
         &self,
         region: &Region,
-        dns_server: &str,
+        ip_version: IpVersion,
+        dualstack: bool,
+        dns: &DnsStrategy,
         desired: Option<u16>,
         rounds: u16,
         concurrency: u16,
         round_delay: Duration,
+        ttl_floor: Duration,
     ) -> u16 {
-        let mut standard_timeout = Duration::from_millis(50);
+        // a single round (as when refreshing) never gets to back off - so we start with
+        // a timeout that suits the strategy
+        let mut standard_timeout = dns.initial_timeout();
 
         for _round in 0..rounds {
             let mut dns_futures = Vec::new();
 
-            for _c in 0..concurrency {
+            for c in 0..concurrency {
                 dns_futures.push(self.populate_a_dns(
-                    dns_server,
+                    dns,
+                    c as usize,
                     region.name().as_ref(),
+                    ip_version,
+                    dualstack,
                     standard_timeout.clone(),
+                    ttl_floor,
                 ));
             }
 
@@ -115,7 +461,8 @@ impl S3IpPool {
                 return now_count;
             }
 
-            sleep(round_delay);
+            // we are in an async function so must not block the runtime thread
+            delay_for(round_delay).await;
         }
 
         // if we fall through to here then we've given up on reaching our 'desired' count
@@ -129,63 +476,72 @@ impl S3IpPool {
     ///
     /// # Examples
     ///
-    /// populate_a_dns("8.8.8.8:53", "ap-southeast-2")
+    /// populate_a_dns(&dns, 0, "ap-southeast-2", IpVersion::V4, false, timeout, ttl_floor)
     async fn populate_a_dns(
         &self,
-        dns_server: &str,
+        dns: &DnsStrategy,
+        attempt: usize,
         bucket_region: &str,
+        ip_version: IpVersion,
+        dualstack: bool,
         max_response_duration: Duration,
+        ttl_floor: Duration,
     ) -> io::Result<u32> {
-        let socket_addr = dns_server.parse().unwrap();
+        let name = random_s3_fqdn(bucket_region, dualstack);
 
-        // the best feature of trust_dns is the timeout that means that we won't hang around
-        // waiting for packets not coming back
-        let stream = UdpClientStream::<UdpSocket>::with_timeout(socket_addr, max_response_duration);
-
-        // this little snippet should get better as tokio settles down.. its not a particularly
-        // pretty pattern that trust_dns uses (though the results are good)
-        let (mut client, bg) = AsyncClient::connect(stream).await?;
+        let mut added_count = 0u32;
 
-        tokio::spawn(bg);
+        for record_type in ip_version.record_types() {
+            // a failure for one record type shouldn't stop us trying the others
+            let answers = match dns
+                .lookup(attempt, name.as_str(), record_type, max_response_duration)
+                .await
+            {
+                Ok(answers) => answers,
+                Err(_) => continue,
+            };
 
-        let name = Name::from_ascii(random_s3_fqdn(bucket_region))?;
+            added_count += self.add_answers(answers, Instant::now(), ttl_floor);
+        }
 
-        // send the query and get a message response, see RecordType for all supported options
-        let query = client.query(name, DNSClass::IN, RecordType::A).await;
+        Ok(added_count)
+    }
 
+    /// Adds the endpoints from a DNS answer to the pool (treating none of them as expiring
+    /// sooner than the floor) and returns the number of new IP addresses that were added.
+    ///
+    fn add_answers(&self, answers: Vec<(IpAddr, u32)>, now: Instant, ttl_floor: Duration) -> u32 {
         let mut added_count = 0u32;
 
-        if query.is_ok() {
-            let response: DnsResponse = query.unwrap();
+        // quick processing of the answers whilst we hold a lock on our db mutex
+        let mut ips = self.ips.lock().unwrap();
 
-            // we will not necessarily only get the DNS answer we ask for
-            let answers: &[Record] = response.answers();
+        for (ip, ttl) in answers {
+            let expires = now + Duration::from_secs(ttl as u64).max(ttl_floor);
 
-            // quick processing of the answers whilst we hold a lock on our db mutex
-            {
-                let mut ips = self.ips.lock().unwrap();
-
-                for ans in answers {
-                    // where the answer fits into the A data structure
-                    // we see if this is a new IP and if so, add it
-                    if let RData::A(ref ip) = ans.rdata() {
-                        if !ips.contains_key(&ip.to_string()) {
-                            ips.insert(ip.to_string(), 0);
-                            added_count += 1;
-                        }
-                    }
-                }
+            let stats = ips.entry(ip.to_string()).or_insert_with(|| {
+                added_count += 1;
+                S3IpStats::new()
+            });
+
+            // seeing an endpoint again extends its life (and brings it back if it
+            // was only retired because it had expired)
+            if stats.expires.map_or(true, |e| e < expires) {
+                stats.expires = Some(expires);
+            }
+            if stats.retired && !stats.is_unhealthy() {
+                stats.retired = false;
             }
         }
 
-        Ok(added_count)
+        added_count
     }
 }
 
 /// Create a random bucket name in the S3 domain space - hoping that
 /// this will maximise our chance of getting new round robin IP addresses for S3 targets
 ///
-fn random_s3_fqdn(br: &str) -> String {
+fn random_s3_fqdn(br: &str, dualstack: bool) -> String {
     let mut rng = thread_rng();
 
     // using a random bucket name increases our chances of avoiding DNS caches along the way
@@ -199,9 +555,171 @@ fn random_s3_fqdn(br: &str) -> String {
     // https://docs.aws.amazon.com/general/latest/gr/rande.html
     // if we use s3-region rather than s3.region then it works for some regions but
     // fails for us-east-1
+    if dualstack {
+        return format!("{}.s3.dualstack.{}.amazonaws.com.", chars, br);
+    }
+
     return format!("{}.s3.{}.amazonaws.com.", chars, br);
 }
 
 //fn print_type_of<T>(_: &T) {
 //    println!("{}", std::any::type_name::<T>())
 //}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use crate::download_block::BlockResult;
+    use crate::s3_ip_pool::{random_s3_fqdn, IpVersion, S3IpPool, S3IpStats};
+    use std::time::{Duration, Instant};
+    use trust_dns_client::rr::RecordType;
+
+    #[test]
+    fn dualstack_fqdn_used_for_ipv6() {
+        assert!(random_s3_fqdn("us-west-2", false).ends_with(".s3.us-west-2.amazonaws.com."));
+        assert!(random_s3_fqdn("us-west-2", true)
+            .ends_with(".s3.dualstack.us-west-2.amazonaws.com."));
+
+        assert_eq!("both".parse::<IpVersion>().unwrap(), IpVersion::Both);
+        assert_eq!(IpVersion::V6.record_types(), vec![RecordType::AAAA]);
+        assert!(IpVersion::V6.requires_dualstack());
+        assert!(!IpVersion::V4.requires_dualstack());
+    }
+
+    #[test]
+    fn expired_ips_are_avoided_then_retired() {
+        let pool = S3IpPool::new();
+
+        let mut expired = S3IpStats::new();
+        expired.expires = Some(Instant::now() - Duration::from_secs(1));
+
+        let mut fresh = S3IpStats::new();
+        fresh.expires = Some(Instant::now() + Duration::from_secs(60));
+        fresh.usage = 5;
+
+        {
+            let mut ips = pool.ips.lock().unwrap();
+            ips.insert("52.216.1.1".to_string(), expired);
+            ips.insert("52.216.2.2".to_string(), fresh);
+        }
+
+        // even though it is more used we choose the endpoint that hasn't expired
+        let (ip, usage) = pool.choose_ip(0);
+
+        assert_eq!(ip.to_string(), "52.216.2.2");
+        assert_eq!(usage, 6);
+
+        assert_eq!(pool.retire_ips(), 1);
+        assert_eq!(pool.active_ip_count(), 1);
+        assert_eq!(pool.ip_count(), 2);
+    }
+
+    #[test]
+    fn short_dns_ttls_are_raised_to_the_floor() {
+        let pool = S3IpPool::new();
+
+        let now = Instant::now();
+        let floor = Duration::from_secs(60);
+
+        let answers = vec![
+            ("52.216.1.1".parse().unwrap(), 5),
+            ("52.216.2.2".parse().unwrap(), 300),
+        ];
+
+        assert_eq!(pool.add_answers(answers, now, floor), 2);
+
+        let ips = pool.ips.lock().unwrap();
+
+        assert_eq!(ips["52.216.1.1"].expires, Some(now + floor));
+        assert_eq!(ips["52.216.2.2"].expires, Some(now + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn unhealthy_ips_are_retired_but_never_the_last() {
+        let pool = S3IpPool::new();
+
+        {
+            let mut ips = pool.ips.lock().unwrap();
+
+            for ip in &["52.216.1.1", "52.216.2.2"] {
+                let mut stats = S3IpStats::new();
+                stats.errors = 5;
+                ips.insert(ip.to_string(), stats);
+            }
+        }
+
+        assert_eq!(pool.retire_ips(), 1);
+        assert_eq!(pool.active_ip_count(), 1);
+        assert_eq!(pool.retire_ips(), 0);
+    }
+
+    #[test]
+    fn probes_record_connect_and_tls_times_apart() {
+        let pool = S3IpPool::new();
+
+        let ips = vec!["52.216.1.1".parse().unwrap(), "52.216.2.2".parse().unwrap()];
+
+        pool.add_ips(&ips);
+
+        pool.record_probe(
+            &ips[0],
+            Some((Duration::from_millis(2), Some(Duration::from_millis(9)))),
+        );
+        pool.record_probe(&ips[1], None);
+
+        let stats = pool.ips.lock().unwrap();
+
+        assert_eq!(stats["52.216.1.1"].probe_rtt, Some(Duration::from_millis(2)));
+        assert_eq!(stats["52.216.1.1"].probe_tls, Some(Duration::from_millis(9)));
+        assert_eq!(stats["52.216.2.2"].probe_rtt, None);
+        assert_eq!(stats["52.216.2.2"].errors, 1);
+    }
+
+    #[test]
+    fn block_results_are_recorded_against_their_ip() {
+        let pool = S3IpPool::new();
+
+        pool.ips
+            .lock()
+            .unwrap()
+            .insert("52.216.1.1".to_string(), S3IpStats::new());
+
+        let result = BlockResult {
+            slot: 0,
+            s3_socket_addr: "52.216.1.1:443".parse().unwrap(),
+            bytes: 8 * 1024 * 1024,
+            duration: 1_000_000_000,
+            tls_setup: 20_000_000,
+        };
+
+        pool.record_block(&result);
+        pool.record_block(&result);
+        pool.record_error(&result.s3_socket_addr, &anyhow!("connection reset"));
+
+        // endpoints we don't know about are ignored (though not why their block failed)
+        pool.record_error(&"10.0.0.1:443".parse().unwrap(), &anyhow!("timed out"));
+
+        assert_eq!(
+            pool.block_errors(),
+            vec![
+                "52.216.1.1:443 - connection reset".to_string(),
+                "10.0.0.1:443 - timed out".to_string()
+            ]
+        );
+
+        let ips = pool.ips.lock().unwrap();
+        let stats = ips.get("52.216.1.1").unwrap();
+
+        assert_eq!(ips.len(), 1);
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.bytes, 16 * 1024 * 1024);
+        assert_eq!(stats.errors, 1);
+
+        // histograms are only accurate to 3 significant figures
+        let p50 = stats.throughput_at_quantile(0.5).unwrap() as f64;
+        assert!((p50 - 8388608.0).abs() / 8388608.0 < 0.001);
+        let tls_setup = stats.tls_setup_mean().unwrap().as_secs_f64();
+        assert!((tls_setup - 0.020).abs() / 0.020 < 0.001);
+    }
+}
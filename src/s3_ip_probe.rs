@@ -0,0 +1,89 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use rustls::ClientConfig;
+use tokio::time::timeout;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use crate::proxy::{connect, ProxySettings};
+use crate::s3_ip_pool::S3IpPool;
+use crate::tcp_socket::SocketSettings;
+
+/// Measures (in parallel) how long it takes to connect and then TLS handshake with each
+/// of the given S3 endpoints on the given port, recording the outcomes in the pool. Without
+/// a TLS config (when talking plain HTTP) we only measure the connect.
+///
+/// As we use our shared TLS config, a successful probe also leaves a TLS session
+/// that later connections can resume.
+///
+pub async fn probe_ips(
+    pool: &S3IpPool,
+    ips: Vec<IpAddr>,
+    port: u16,
+    hostname: &str,
+    tls_config: Option<Arc<ClientConfig>>,
+    proxy: Option<&ProxySettings>,
+    sockets: &SocketSettings,
+    max_duration: Duration,
+) {
+    let tls_connector = tls_config.map(TlsConnector::from);
+
+    // we spread our probes across the slots (and so across any sources we bind to)
+    let probes = ips.iter().enumerate().map(|(slot, ip)| {
+        probe_ip(
+            slot,
+            SocketAddr::new(*ip, port),
+            tls_connector.as_ref(),
+            hostname,
+            proxy,
+            sockets,
+            max_duration,
+        )
+    });
+
+    let outcomes = join_all(probes).await;
+
+    for (ip, outcome) in ips.iter().zip(outcomes) {
+        pool.record_probe(ip, outcome);
+    }
+}
+
+/// Returns how long it took to connect to a single endpoint and then how long the TLS
+/// handshake took (if we do one), or None if we couldn't do both in the given time.
+///
+async fn probe_ip(
+    slot: usize,
+    s3_socket_addr: SocketAddr,
+    tls_connector: Option<&TlsConnector>,
+    hostname: &str,
+    proxy: Option<&ProxySettings>,
+    sockets: &SocketSettings,
+    max_duration: Duration,
+) -> Option<(Duration, Option<Duration>)> {
+    let domain = DNSNameRef::try_from_ascii_str(hostname).ok()?;
+
+    let handshake = async {
+        let started = Instant::now();
+
+        let tcp_stream = connect(proxy, sockets, slot, s3_socket_addr, hostname).await?;
+
+        let connected = Instant::now();
+
+        match tls_connector {
+            Some(tls_connector) => {
+                tls_connector.connect(domain, tcp_stream).await?;
+
+                Ok((connected - started, Some(connected.elapsed())))
+            }
+            None => Ok::<_, std::io::Error>((connected - started, None)),
+        }
+    };
+
+    match timeout(max_duration, handshake).await {
+        Ok(Ok(times)) => Some(times),
+        _ => None,
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use hyper::client::HttpConnector;
+use hyper_proxy::{Custom, Intercept, Proxy, ProxyConnector};
+use hyper_rustls::HttpsConnector;
+use rusoto_core::HttpClient;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{Certificate, ClientConfig, ClientSessionMemoryCache, PrivateKey};
+use typed_headers::Credentials;
+
+use crate::config::Config;
+use crate::proxy::ProxySettings;
+
+// where the operating system keeps its bundle of trusted CA certificates (which
+// varies by distribution)
+const NATIVE_CA_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+/// The HTTP client we use for AWS API calls.
+///
+pub type AwsHttpClient = HttpClient<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+/// The certificates we use for TLS beyond the webpki roots that we always trust.
+///
+#[derive(Clone, Default)]
+pub struct TlsCertificates {
+    // extra CA certificates (DER encoded) that we trust
+    roots: Vec<Vec<u8>>,
+
+    // the certificate chain and key we authenticate ourselves with (if any)
+    client: Option<(Vec<Certificate>, PrivateKey)>,
+}
+
+impl TlsCertificates {
+    /// Loads the certificates described by config.
+    ///
+    pub fn load(config: &Config) -> Result<TlsCertificates, String> {
+        let mut roots = vec![];
+
+        if config.native_roots {
+            let bundle = NATIVE_CA_BUNDLES
+                .iter()
+                .map(Path::new)
+                .find(|p| p.exists())
+                .ok_or_else(|| String::from("No operating system CA bundle could be found"))?;
+
+            roots.extend(load_certs(bundle)?.into_iter().map(|c| c.0));
+        }
+
+        if let Some(ca_bundle) = &config.ca_bundle {
+            roots.extend(load_certs(ca_bundle)?.into_iter().map(|c| c.0));
+        }
+
+        let client = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => Some((load_certs(cert)?, load_key(key)?)),
+            (None, None) => None,
+            _ => {
+                return Err(String::from(
+                    "A client certificate and client key must be given together",
+                ))
+            }
+        };
+
+        Ok(TlsCertificates { roots, client })
+    }
+
+    /// Returns true if we have anything other than the defaults.
+    ///
+    pub fn is_custom(&self) -> bool {
+        !self.roots.is_empty() || self.client.is_some()
+    }
+}
+
+/// Returns the TLS client config shared by every connection we make to S3 - with a
+/// session cache big enough that each of our connections can resume a TLS session
+/// rather than doing a full handshake.
+///
+pub fn create_tls_config(
+    config: &Config,
+    certificates: &TlsCertificates,
+) -> Result<Arc<ClientConfig>, String> {
+    let mut tls_config = ClientConfig::new();
+
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+    add_roots(&certificates.roots, |der| {
+        tls_config
+            .root_store
+            .add(&Certificate(der.to_vec()))
+            .is_ok()
+    })?;
+
+    if let Some((chain, key)) = &certificates.client {
+        tls_config
+            .set_single_client_cert(chain.clone(), key.clone())
+            .map_err(|e| format!("Unable to use the client certificate - {}", e))?;
+    }
+
+    tls_config.set_persistence(ClientSessionMemoryCache::new(
+        (config.s3_connections as usize * 2).max(32),
+    ));
+
+    Ok(Arc::new(tls_config))
+}
+
+/// Returns the HTTP client for the AWS API calls we make via rusoto - going through
+/// the proxy if there is one. Rusoto uses its own (older) version of rustls and so
+/// needs its own TLS config.
+///
+pub fn create_http_client(
+    certificates: &TlsCertificates,
+    proxy: Option<&ProxySettings>,
+) -> Result<AwsHttpClient, String> {
+    // without any certificates of our own we keep the rusoto defaults
+    let https_connector = if certificates.is_custom() {
+        let mut tls_config = rusoto_rustls::ClientConfig::new();
+
+        tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+
+        add_roots(&certificates.roots, |der| {
+            tls_config
+                .root_store
+                .add(&rusoto_rustls::Certificate(der.to_vec()))
+                .is_ok()
+        })?;
+
+        if let Some((chain, key)) = &certificates.client {
+            tls_config
+                .set_single_client_cert(
+                    chain
+                        .iter()
+                        .map(|c| rusoto_rustls::Certificate(c.0.clone()))
+                        .collect(),
+                    rusoto_rustls::PrivateKey(key.0.clone()),
+                )
+                .map_err(|e| format!("Unable to use the client certificate - {}", e))?;
+        }
+
+        // rusoto only speaks HTTP/1.1
+        tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let mut http_connector = HttpConnector::new();
+        http_connector.enforce_http(false);
+
+        HttpsConnector::from((http_connector, tls_config))
+    } else {
+        HttpsConnector::new()
+    };
+
+    // a proxy connector without any proxies connects directly
+    let mut proxy_connector = ProxyConnector::unsecured(https_connector);
+
+    if let Some(proxy) = proxy {
+        let settings = proxy.clone();
+
+        let intercept = Intercept::Custom(Custom::from(
+            move |_scheme: Option<&str>, host: Option<&str>, _port: Option<u16>| {
+                host.map_or(true, |h| !settings.bypasses(h))
+            },
+        ));
+
+        let uri = proxy
+            .url()
+            .parse()
+            .map_err(|e| format!("The proxy {} is not valid - {}", proxy.url(), e))?;
+
+        let mut hyper_proxy = Proxy::new(intercept, uri);
+
+        if let Some((username, password)) = &proxy.credentials {
+            let credentials = Credentials::basic(username, password)
+                .map_err(|e| format!("The proxy credentials are not valid - {}", e))?;
+
+            hyper_proxy.set_authorization(credentials);
+        }
+
+        proxy_connector.add_proxy(hyper_proxy);
+    }
+
+    Ok(HttpClient::from_connector(proxy_connector))
+}
+
+/// Adds each of the (DER encoded) CA certificates to a root store using add - which
+/// returns false for a certificate it can't use. Bundles (especially those of the OS)
+/// can contain certificates webpki won't parse, which we skip as long as some of them
+/// are usable.
+///
+fn add_roots(roots: &[Vec<u8>], mut add: impl FnMut(&[u8]) -> bool) -> Result<usize, String> {
+    let added = roots.iter().filter(|der| add(der.as_slice())).count();
+
+    if added == 0 && !roots.is_empty() {
+        return Err(String::from(
+            "None of the given CA certificates could be used",
+        ));
+    }
+
+    Ok(added)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file =
+        File::open(path).map_err(|e| format!("Unable to open {} - {}", path.display(), e))?;
+
+    let loaded = certs(&mut BufReader::new(file))
+        .map_err(|_| format!("Unable to parse the certificates in {}", path.display()))?;
+
+    if loaded.is_empty() {
+        return Err(format!("No certificates were found in {}", path.display()));
+    }
+
+    Ok(loaded)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let read_keys = |pkcs8: bool| {
+        let file =
+            File::open(path).map_err(|e| format!("Unable to open {} - {}", path.display(), e))?;
+
+        let mut reader = BufReader::new(file);
+
+        if pkcs8 {
+            pkcs8_private_keys(&mut reader)
+        } else {
+            rsa_private_keys(&mut reader)
+        }
+        .map_err(|_| format!("Unable to parse the private key in {}", path.display()))
+    };
+
+    // keys can be in either PKCS8 or the older RSA format
+    let key = match read_keys(true)?.into_iter().next() {
+        Some(key) => Some(key),
+        None => read_keys(false)?.into_iter().next(),
+    };
+
+    key.ok_or_else(|| format!("No private key was found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use crate::config::Config;
+    use crate::setup_tls::{add_roots, TlsCertificates};
+
+    #[test]
+    fn defaults_are_not_custom() {
+        let certificates = TlsCertificates::load(&Config::default()).unwrap();
+
+        assert!(!certificates.is_custom());
+    }
+
+    #[test]
+    fn unusable_roots_are_skipped_unless_none_are_usable() {
+        let roots = vec![b"good".to_vec(), b"bad".to_vec()];
+
+        let added = add_roots(&roots, |der| der == b"good").unwrap();
+        assert_eq!(added, 1);
+
+        assert!(add_roots(&roots, |_| false).is_err());
+        assert_eq!(add_roots(&[], |_| false).unwrap(), 0);
+    }
+
+    #[test]
+    fn bad_bundles_are_errors() {
+        let mut config = Config::default();
+
+        config.ca_bundle = Some("/does/not/exist.pem".into());
+        assert!(TlsCertificates::load(&config).is_err());
+
+        let mut empty = NamedTempFile::new().unwrap();
+        writeln!(empty, "not a certificate").unwrap();
+
+        config.ca_bundle = Some(empty.path().to_path_buf());
+        assert!(TlsCertificates::load(&config).is_err());
+
+        // a client certificate without a key is no use to us
+        let mut config = Config::default();
+        config.client_cert = Some(empty.path().to_path_buf());
+        assert!(TlsCertificates::load(&config).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::future::Future;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use futures::future::{select, Either};
+use futures::pin_mut;
+use metrics_runtime::Sink;
+use tokio::time::delay_for;
+
+use crate::metric_names::{
+    METRIC_SLOT_TCP_RCV_RTT, METRIC_SLOT_TCP_RCV_RTT_MICROS, METRIC_SLOT_TCP_RCV_SPACE_BYTES,
+    METRIC_SLOT_TCP_RECEIVED_BYTES,
+};
+
+/// The kernel's view of the receiving side of a TCP connection at a moment in time (from
+/// TCP_INFO). We only receive the body of a download, so the send side (congestion window,
+/// retransmits, delivery rate) says little more than how our requests and acks are doing.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcpInfoSample {
+    // the round trip time as estimated by the receiver (0 until enough data has arrived)
+    pub rcv_rtt_micros: u32,
+
+    // the receive buffer space the kernel is autotuning towards
+    pub rcv_space_bytes: u32,
+
+    // the bytes received over the life of the connection (which older kernels don't provide)
+    pub bytes_received: u64,
+}
+
+/// Samples TCP_INFO of the socket of a connection into the metrics of its slot.
+///
+pub struct TcpInfoRecorder {
+    fd: RawFd,
+    sink: Sink,
+
+    // the bytes received at our last sample, so that the slot counter only
+    // increases by the bytes received since
+    received: u64,
+}
+
+impl TcpInfoRecorder {
+    /// Returns a recorder for the socket fd that records into the (slot scoped) sink.
+    /// The fd must remain open for as long as the recorder is used.
+    ///
+    pub fn new(fd: RawFd, sink: &Sink) -> TcpInfoRecorder {
+        TcpInfoRecorder {
+            fd,
+            sink: sink.clone(),
+            received: 0,
+        }
+    }
+
+    /// Samples TCP_INFO now and records it - doing nothing if it can't be sampled.
+    ///
+    pub fn record(&mut self) {
+        let sample = match sample_tcp_info(self.fd) {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        if sample.rcv_rtt_micros > 0 {
+            self.sink
+                .record_value(METRIC_SLOT_TCP_RCV_RTT, sample.rcv_rtt_micros as u64 * 1000);
+            self.sink
+                .update_gauge(METRIC_SLOT_TCP_RCV_RTT_MICROS, sample.rcv_rtt_micros as i64);
+        }
+
+        self.sink.update_gauge(
+            METRIC_SLOT_TCP_RCV_SPACE_BYTES,
+            sample.rcv_space_bytes as i64,
+        );
+
+        let received = sample.bytes_received.saturating_sub(self.received);
+
+        self.sink
+            .increment_counter(METRIC_SLOT_TCP_RECEIVED_BYTES, received);
+
+        self.received = sample.bytes_received;
+    }
+
+    /// Runs future to completion while recording a sample every interval - and a final
+    /// sample once it completes. An interval of zero only takes the final sample, and
+    /// no interval at all takes no samples.
+    ///
+    pub async fn record_during<F: Future>(
+        &mut self,
+        interval: Option<Duration>,
+        future: F,
+    ) -> F::Output {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return future.await,
+        };
+
+        // the connection may have carried earlier blocks, whose bytes aren't ours to count
+        if let Some(sample) = sample_tcp_info(self.fd) {
+            self.received = sample.bytes_received;
+        }
+
+        let output = if interval > Duration::from_secs(0) {
+            let recorder = &mut *self;
+
+            let sampling = async move {
+                loop {
+                    delay_for(interval).await;
+                    recorder.record();
+                }
+            };
+
+            pin_mut!(future, sampling);
+
+            match select(future, sampling).await {
+                Either::Left((output, _)) => output,
+                Either::Right(_) => unreachable!("sampling never finishes"),
+            }
+        } else {
+            future.await
+        };
+
+        self.record();
+
+        output
+    }
+}
+
+/// Returns the TCP_INFO of the socket - or None if it can't be had (which is always
+/// the case other than on Linux).
+///
+#[cfg(target_os = "linux")]
+pub fn sample_tcp_info(fd: RawFd) -> Option<TcpInfoSample> {
+    use nix::libc::{c_void, getsockopt, socklen_t, IPPROTO_TCP, TCP_INFO};
+
+    let mut info = RawTcpInfo::default();
+    let mut len = std::mem::size_of::<RawTcpInfo>() as socklen_t;
+
+    // older kernels fill in less of the struct, leaving the rest zero
+    let result = unsafe {
+        getsockopt(
+            fd,
+            IPPROTO_TCP,
+            TCP_INFO,
+            &mut info as *mut RawTcpInfo as *mut c_void,
+            &mut len,
+        )
+    };
+
+    if result != 0 {
+        return None;
+    }
+
+    Some(TcpInfoSample {
+        rcv_rtt_micros: info.rcv_rtt,
+        rcv_space_bytes: info.rcv_space,
+        bytes_received: info.bytes_received,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn sample_tcp_info(_fd: RawFd) -> Option<TcpInfoSample> {
+    None
+}
+
+/// The start of struct tcp_info from linux/tcp.h - up to the bytes received (which is
+/// all we need). libc stops short of the newer fields.
+///
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct RawTcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    flags: u8,
+
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+
+    rcv_rtt: u32,
+    rcv_space: u32,
+
+    total_retrans: u32,
+
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+
+    use crate::tcp_info::sample_tcp_info;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn samples_a_connected_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        server.write_all(b"hello").unwrap();
+
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).unwrap();
+
+        let sample = sample_tcp_info(stream.as_raw_fd()).unwrap();
+
+        // the receive side is what a download cares about
+        assert!(sample.rcv_space_bytes > 0);
+        assert_eq!(sample.bytes_received, 5);
+
+        // whereas something that isn't a socket has no TCP_INFO at all
+        let file = tempfile::tempfile().unwrap();
+        assert!(sample_tcp_info(file.as_raw_fd()).is_none());
+    }
+}
@@ -0,0 +1,299 @@
+#[cfg(target_os = "linux")]
+use std::ffi::OsString;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use nix::sys::socket::SockAddr as NixSockAddr;
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{setsockopt, sockopt::TcpCongestion};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+/// How we set up each TCP socket that we connect to S3 (or our proxy) with.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketSettings {
+    // the size of the kernel receive buffer (SO_RCVBUF)
+    pub recv_buffer_size: Option<usize>,
+
+    // if true we turn off Nagle's algorithm (TCP_NODELAY)
+    pub nodelay: bool,
+
+    // the TCP congestion control algorithm (eg: bbr)
+    pub congestion: Option<String>,
+
+    // the sources (IP addresses or interfaces) we bind to - where the slots are spread
+    // across the sources
+    sources: Vec<BindSource>,
+}
+
+/// A source that connections are bound to.
+///
+#[derive(Clone, Debug, PartialEq)]
+struct BindSource {
+    name: String,
+
+    // true if the source is an interface (rather than an IP address) - in which case we
+    // also bind to the device so that our traffic leaves by it whatever the routing
+    interface: bool,
+
+    ips: Vec<IpAddr>,
+}
+
+impl SocketSettings {
+    /// Returns the socket settings described by config - or an error if any of the
+    /// sources to bind to are neither an IP address nor an interface with an address.
+    ///
+    pub fn from_config(config: &Config) -> Result<SocketSettings, String> {
+        let sources = config
+            .bind
+            .iter()
+            .map(|source| resolve_source(source.as_str()))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(SocketSettings {
+            recv_buffer_size: config
+                .socket_recv_buffer_size_kibs
+                .map(|kibs| (kibs * 1024) as usize),
+            nodelay: config.tcp_nodelay,
+            congestion: config.tcp_congestion.clone(),
+            sources,
+        })
+    }
+
+    /// Opens a TCP connection to the given address for the given slot.
+    ///
+    pub async fn connect(&self, slot: usize, addr: SocketAddr) -> io::Result<TcpStream> {
+        if *self == SocketSettings::default() {
+            return TcpStream::connect(addr).await;
+        }
+
+        let domain = if addr.is_ipv4() {
+            Domain::ipv4()
+        } else {
+            Domain::ipv6()
+        };
+
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+        // the receive buffer must be set before connecting as it decides the window scaling
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+
+        if let Some(congestion) = &self.congestion {
+            set_congestion(&socket, congestion.as_str())?;
+        }
+
+        if let Some((source, ip)) = self.source_for(slot, addr)? {
+            if source.interface {
+                bind_to_device(&socket, source.name.as_str())?;
+            }
+
+            socket.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?;
+        }
+
+        TcpStream::connect_std(socket.into_tcp_stream(), &addr).await
+    }
+
+    /// Returns the source the given slot should bind to when connecting to addr, along
+    /// with the address of the same IP version from it to bind to.
+    ///
+    fn source_for(
+        &self,
+        slot: usize,
+        addr: SocketAddr,
+    ) -> io::Result<Option<(&BindSource, IpAddr)>> {
+        if self.sources.is_empty() {
+            return Ok(None);
+        }
+
+        let source = &self.sources[slot % self.sources.len()];
+
+        source
+            .ips
+            .iter()
+            .find(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .map(|ip| Some((source, *ip)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!(
+                        "The source `{}` has no address to reach {} from",
+                        source.name, addr
+                    ),
+                )
+            })
+    }
+}
+
+/// Binds the socket to a network interface (SO_BINDTODEVICE) - as binding to only an
+/// address of the interface leaves the routing table to choose the way out.
+///
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    use nix::libc::{c_void, setsockopt, socklen_t, SOL_SOCKET, SO_BINDTODEVICE};
+
+    let result = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_BINDTODEVICE,
+            interface.as_ptr() as *const c_void,
+            interface.len() as socklen_t,
+        )
+    };
+
+    if result != 0 {
+        let e = io::Error::last_os_error();
+
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "Unable to bind to the interface `{}` (before Linux 5.7 this needs CAP_NET_RAW) - {}",
+                interface, e
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!(
+            "Binding to the interface `{}` is only supported on Linux (bind to its address instead)",
+            interface
+        ),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_congestion(socket: &Socket, congestion: &str) -> io::Result<()> {
+    setsockopt(
+        socket.as_raw_fd(),
+        TcpCongestion,
+        &OsString::from(congestion),
+    )
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Unable to use the TCP congestion control `{}` (is its module loaded?) - {}",
+                congestion, e
+            ),
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_congestion(_socket: &Socket, _congestion: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Setting the TCP congestion control is only supported on Linux",
+    ))
+}
+
+/// Returns the source with its addresses - where a source is either an IP address or the
+/// name of a network interface (eg: eth1).
+///
+fn resolve_source(source: &str) -> Result<BindSource, String> {
+    if let Ok(ip) = source.parse::<IpAddr>() {
+        return Ok(BindSource {
+            name: String::from(source),
+            interface: false,
+            ips: vec![ip],
+        });
+    }
+
+    let interfaces = nix::ifaddrs::getifaddrs()
+        .map_err(|e| format!("Unable to list the network interfaces - {}", e))?;
+
+    let ips: Vec<IpAddr> = interfaces
+        .filter(|i| i.interface_name == source)
+        .filter_map(|i| match i.address {
+            Some(NixSockAddr::Inet(inet)) => Some(inet.to_std().ip()),
+            _ => None,
+        })
+        .collect();
+
+    if ips.is_empty() {
+        return Err(format!(
+            "The value `{}` is neither an IP address nor a network interface with an address",
+            source
+        ));
+    }
+
+    Ok(BindSource {
+        name: String::from(source),
+        interface: true,
+        ips,
+    })
+}
+
+/// Returns the sources in a comma separated list.
+///
+pub(crate) fn parse_sources(sources: &str) -> Vec<String> {
+    sources
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::tcp_socket::{parse_sources, SocketSettings};
+
+    #[test]
+    fn slots_are_spread_across_sources() {
+        let mut config = Config::default();
+        config.bind = parse_sources("10.0.1.5, 10.0.2.5,fd00::5");
+
+        let settings = SocketSettings::from_config(&config).unwrap();
+
+        let v4 = "52.218.1.1:443".parse().unwrap();
+        let v6 = "[2600:1fa0::1]:443".parse().unwrap();
+
+        let source = |slot| {
+            settings
+                .source_for(slot, v4)
+                .unwrap()
+                .unwrap()
+                .1
+                .to_string()
+        };
+
+        assert_eq!(source(0), "10.0.1.5");
+        assert_eq!(source(1), "10.0.2.5");
+        assert_eq!(source(3), "10.0.1.5");
+
+        // a source without an address of the right IP version can't be used
+        assert!(settings.source_for(2, v4).is_err());
+        assert!(settings.source_for(2, v6).unwrap().is_some());
+
+        // only an interface is bound to as a device
+        config.bind = parse_sources("lo");
+
+        let settings = SocketSettings::from_config(&config).unwrap();
+        let (source, ip) = settings.source_for(0, v4).unwrap().unwrap();
+
+        assert!(source.interface);
+        assert!(ip.is_loopback());
+
+        config.bind = parse_sources("not-an-interface-s3bfg");
+        assert!(SocketSettings::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use metrics_runtime::Sink;
+use serde_json::{json, Value};
+
+/// A single span of time spent by a slot in one phase of downloading a block.
+///
+#[derive(Debug, Clone)]
+struct TraceSpan {
+    name: &'static str,
+    slot: usize,
+    block_start: u64,
+    block_length: u64,
+    // nanosecond timestamps from the metrics clock
+    start: u64,
+    end: u64,
+    // true if the phase ended in an error
+    failed: bool,
+}
+
+/// A thread-safe collector of the spans of every block downloaded during a transfer,
+/// that can be written out in the Chrome `trace_event` JSON format (which can be opened in
+/// Perfetto or chrome://tracing).
+///
+#[derive(Debug, Clone)]
+pub struct TraceRecorder {
+    spans: Arc<Mutex<Vec<TraceSpan>>>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> TraceRecorder {
+        TraceRecorder {
+            spans: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Returns the trace for an individual block - which will record spans into
+    /// this recorder.
+    ///
+    pub fn block(&self, slot: usize, block_start: u64, block_length: u64) -> BlockTrace {
+        BlockTrace {
+            recorder: Some(self.clone()),
+            slot,
+            block_start,
+            block_length,
+        }
+    }
+
+    /// Writes all the spans recorded so far as a Chrome trace to the given path.
+    ///
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+
+        serde_json::to_writer(writer, &self.to_json())?;
+
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        let spans = self.spans.lock().unwrap();
+
+        // our timestamps are from an arbitrary clock so we make them relative to
+        // the very first thing we traced
+        let origin = spans.iter().map(|s| s.start).min().unwrap_or(0);
+
+        let mut slots: Vec<usize> = spans.iter().map(|s| s.slot).collect();
+        slots.sort();
+        slots.dedup();
+
+        let mut events: Vec<Value> = slots
+            .iter()
+            .map(|slot| {
+                json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 1,
+                    "tid": slot,
+                    "args": { "name": format!("slot-{}", slot) },
+                })
+            })
+            .collect();
+
+        for s in spans.iter() {
+            events.push(json!({
+                "name": s.name,
+                "cat": "block",
+                "ph": "X",
+                "pid": 1,
+                "tid": s.slot,
+                "ts": (s.start - origin) as f64 / 1000.0,
+                "dur": (s.end.saturating_sub(s.start)) as f64 / 1000.0,
+                "args": {
+                    "block_start": s.block_start,
+                    "block_length": s.block_length,
+                    "failed": s.failed,
+                },
+            }));
+        }
+
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+}
+
+/// Records the spans of a single block - doing nothing if tracing is not enabled.
+///
+#[derive(Debug, Clone)]
+pub struct BlockTrace {
+    recorder: Option<TraceRecorder>,
+    slot: usize,
+    block_start: u64,
+    block_length: u64,
+}
+
+impl BlockTrace {
+    /// Returns a block trace that records nothing.
+    ///
+    pub fn disabled() -> BlockTrace {
+        BlockTrace {
+            recorder: None,
+            slot: 0,
+            block_start: 0,
+            block_length: 0,
+        }
+    }
+
+    /// Records that the block spent from start to end (nanoseconds as given by
+    /// a metrics sink) in the given phase.
+    ///
+    pub fn span(&self, name: &'static str, start: u64, end: u64) {
+        self.record(name, start, end, false);
+    }
+
+    /// Starts a span of the given phase that is recorded when it is ended - or if the
+    /// phase fails (say by returning early with an error) when it is dropped, in which
+    /// case it is marked as failed.
+    ///
+    pub fn start(&self, name: &'static str, start: u64, sink: &Sink) -> PhaseSpan<'_> {
+        PhaseSpan {
+            trace: self,
+            name,
+            start,
+            // we only need the clock of the sink if we are recording
+            sink: self.recorder.as_ref().map(|_| sink.clone()),
+            ended: false,
+        }
+    }
+
+    fn record(&self, name: &'static str, start: u64, end: u64, failed: bool) {
+        if let Some(recorder) = &self.recorder {
+            recorder.spans.lock().unwrap().push(TraceSpan {
+                name,
+                slot: self.slot,
+                block_start: self.block_start,
+                block_length: self.block_length,
+                start,
+                end,
+                failed,
+            });
+        }
+    }
+}
+
+/// A span of a block that has started but not yet ended.
+///
+pub struct PhaseSpan<'a> {
+    trace: &'a BlockTrace,
+    name: &'static str,
+    start: u64,
+    sink: Option<Sink>,
+    ended: bool,
+}
+
+impl PhaseSpan<'_> {
+    /// Records the span as having successfully ended at the given time.
+    ///
+    pub fn end(mut self, end: u64) {
+        self.trace.record(self.name, self.start, end, false);
+        self.ended = true;
+    }
+}
+
+impl Drop for PhaseSpan<'_> {
+    fn drop(&mut self) {
+        if let (Some(sink), false) = (&self.sink, self.ended) {
+            self.trace.record(self.name, self.start, sink.now(), true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_runtime::Receiver;
+
+    use crate::trace_events::TraceRecorder;
+
+    #[test]
+    fn spans_are_relative_and_in_microseconds() {
+        let recorder = TraceRecorder::new();
+
+        recorder.block(2, 0, 100).span("tcp_connect", 5_000_000, 6_000_000);
+        recorder.block(3, 100, 100).span("tls_handshake", 7_000_000, 7_500_000);
+
+        let json = recorder.to_json();
+        let events = json["traceEvents"].as_array().unwrap();
+
+        // two thread name events plus our two spans
+        assert_eq!(events.len(), 4);
+
+        assert_eq!(events[2]["name"], "tcp_connect");
+        assert_eq!(events[2]["tid"], 2);
+        assert_eq!(events[2]["ts"], 0.0);
+        assert_eq!(events[2]["dur"], 1000.0);
+
+        assert_eq!(events[3]["ts"], 2000.0);
+        assert_eq!(events[3]["dur"], 500.0);
+    }
+
+    #[test]
+    fn phases_that_fail_are_still_traced() {
+        let recorder = TraceRecorder::new();
+        let sink = Receiver::builder().build().unwrap().sink();
+
+        let block = recorder.block(1, 0, 100);
+
+        block.start("tcp_connect", sink.now(), &sink).end(sink.now());
+
+        // a phase that returns early with an error never gets to end its span
+        {
+            let _span = block.start("tls_handshake", sink.now(), &sink);
+        }
+
+        let json = recorder.to_json();
+        let events = json["traceEvents"].as_array().unwrap();
+
+        assert_eq!(events.len(), 3);
+
+        assert_eq!(events[1]["name"], "tcp_connect");
+        assert_eq!(events[1]["args"]["failed"], false);
+
+        assert_eq!(events[2]["name"], "tls_handshake");
+        assert_eq!(events[2]["args"]["failed"], true);
+    }
+}
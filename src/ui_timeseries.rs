@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use metrics_core::Observe;
+use metrics_runtime::Controller;
+
+use crate::metric_observer_timeseries::TimeseriesObserver;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records a CSV time series of the progress of the transfer - one row per second - so that
+/// the shape of the transfer (slow start, plateaus, tail drop off) can be plotted.
+///
+pub struct TimeseriesRecorder {
+    stop: Sender<()>,
+    worker: JoinHandle<io::Result<()>>,
+}
+
+impl TimeseriesRecorder {
+    /// Starts a (non tokio runtime) thread writing the time series to the given file.
+    ///
+    pub fn start(controller: Controller, path: &Path, slots: usize) -> io::Result<TimeseriesRecorder> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        write!(
+            writer,
+            "elapsed_secs,total_bytes,bytes_per_sec,active_connections,errors"
+        )?;
+        for slot in 0..slots {
+            write!(writer, ",slot_{}_bytes", slot)?;
+        }
+        for slot in 0..slots {
+            write!(
+                writer,
+                ",slot_{}_rcv_rtt_micros,slot_{}_rcv_space,slot_{}_tcp_received_bytes",
+                slot, slot, slot
+            )?;
+        }
+        writeln!(writer)?;
+
+        let (stop, stopped) = channel();
+
+        let worker = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut last_transferred = 0u64;
+            let mut last_elapsed = 0f64;
+            let mut samples = 0u32;
+
+            loop {
+                samples += 1;
+
+                // aim for exactly one row per second rather than drifting
+                let next_sample = start + SAMPLE_INTERVAL * samples;
+                let wait = next_sample.saturating_duration_since(Instant::now());
+
+                let finished = match stopped.recv_timeout(wait) {
+                    Err(RecvTimeoutError::Timeout) => false,
+                    _ => true,
+                };
+
+                let mut observer = TimeseriesObserver::new();
+
+                controller.observe(&mut observer);
+
+                let elapsed = start.elapsed().as_secs_f64();
+
+                // the final row may come after less than a full interval
+                let interval = elapsed - last_elapsed;
+
+                write!(
+                    writer,
+                    "{:.3},{},{:.0},{},{}",
+                    elapsed,
+                    observer.transferred,
+                    if interval > 0.0 {
+                        (observer.transferred - last_transferred) as f64 / interval
+                    } else {
+                        0.0
+                    },
+                    observer.active_connections,
+                    observer.errors
+                )?;
+                for slot in 0..slots {
+                    write!(
+                        writer,
+                        ",{}",
+                        observer.slot_transferred.get(&slot).unwrap_or(&0)
+                    )?;
+                }
+                for slot in 0..slots {
+                    write!(
+                        writer,
+                        ",{},{},{}",
+                        observer.slot_rcv_rtt_micros.get(&slot).unwrap_or(&0),
+                        observer.slot_rcv_space.get(&slot).unwrap_or(&0),
+                        observer.slot_tcp_received.get(&slot).unwrap_or(&0)
+                    )?;
+                }
+                writeln!(writer)?;
+
+                // rows are flushed as we go so the series is useful even if we are killed
+                writer.flush()?;
+
+                last_transferred = observer.transferred;
+                last_elapsed = elapsed;
+
+                if finished {
+                    return Ok(());
+                }
+            }
+        });
+
+        Ok(TimeseriesRecorder { stop, worker })
+    }
+
+    /// Records a final row and then stops recording.
+    ///
+    pub fn finish(self) -> io::Result<()> {
+        let _ = self.stop.send(());
+
+        self.worker
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "timeseries thread panicked")))
+    }
+}
@@ -2,6 +2,8 @@ use metrics_runtime::{Receiver, Sink};
 use rusoto_core::region::Region::{ApSoutheast2, UsEast1};
 use rusoto_core::Region;
 use rusoto_credential::{AwsCredentials, ChainProvider, ProvideAwsCredentials};
+use s3bfg::disk_writer::{DiskBackend, DiskWriter};
+use s3bfg::download_block::BlockSettings;
 use sha1::{Digest, Sha1};
 use std::net::{SocketAddr, ToSocketAddrs};
 use tempfile::NamedTempFile;
@@ -40,15 +42,15 @@ async fn test_byte_range_access_in_us_east_1() {
         0,
         16384,
         0,
-        false,
-        Some(path.to_owned()),
+        &block_settings(path.to_owned()),
         0,
+        None,
     )
     .await
     .unwrap();
 
     // slot passed in should be the slot returned
-    assert_eq!(5, r);
+    assert_eq!(5, r.slot);
 
     // read back the file
     let contents = fs::read(path).await.unwrap();
@@ -95,14 +97,14 @@ async fn test_part_number_access_in_us_east_1() {
         0,
         3416989,
         375,
-        false,
-        Some(path.to_owned()),
+        &block_settings(path.to_owned()),
         0,
+        None,
     )
     .await
     .unwrap();
 
-    assert_eq!(1, r);
+    assert_eq!(1, r.slot);
 
     let contents = fs::read(path).await.unwrap();
 
@@ -116,6 +118,38 @@ async fn test_part_number_access_in_us_east_1() {
     );
 }
 
+/// Block settings that will write to the given file using typical buffer sizes.
+///
+fn block_settings(output_filename: std::path::PathBuf) -> BlockSettings {
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(output_filename)
+        .unwrap();
+
+    let sink = Receiver::builder().build().unwrap().sink();
+
+    BlockSettings {
+        memory_only: false,
+        disk_writer: Some(
+            DiskWriter::start(file, None, DiskBackend::Threads, 2, 16, 512 * 1024, &sink).unwrap(),
+        ),
+        dualstack: false,
+        network_buffer_size: 256 * 1024,
+        trace: None,
+        tls_config: s3bfg::setup_tls::create_tls_config(
+            &s3bfg::config::Config::default(),
+            &s3bfg::setup_tls::TlsCertificates::default(),
+        )
+        .unwrap(),
+        prewarmed: None,
+        no_tls: false,
+        port: 443,
+        proxy: None,
+        sockets: Default::default(),
+        tcp_info_interval: None,
+    }
+}
+
 /// Do the basic setup to perform S3 testing in a particular region.
 ///
 async fn setup(region: &Region) -> (Sink, AwsCredentials, SocketAddr) {
@@ -13,3 +13,62 @@ fn one_of_the_files_must_be_in_s3() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn json_reports_are_all_that_goes_to_stdout() -> Result<(), Box<dyn std::error::Error>> {
+    let home = tempfile::tempdir()?;
+
+    // with no way of finding credentials the run fails before any transfer
+    let mut cmd = Command::cargo_bin("s3bfg")?;
+
+    cmd.env_clear()
+        .env("HOME", home.path())
+        .env("AWS_EC2_METADATA_DISABLED", "true")
+        .arg("--not-ec2")
+        .arg("--report")
+        .arg("json")
+        .arg("s3://a-bucket/a-key")
+        .arg(home.path().join("destfile.txt"));
+
+    let output = cmd.output()?;
+
+    assert!(!output.status.success());
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+    assert_eq!(report["success"], false);
+    assert_eq!(report["errors"].as_array().unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn flags_set_in_a_config_file_can_be_turned_off() -> Result<(), Box<dyn std::error::Error>> {
+    let home = tempfile::tempdir()?;
+    let config_file = home.path().join("s3bfg.yaml");
+
+    std::fs::write(&config_file, "verify: true\ntcp-nodelay: true\n")?;
+
+    let mut cmd = Command::cargo_bin("s3bfg")?;
+
+    cmd.env_clear()
+        .env("HOME", home.path())
+        .env("AWS_EC2_METADATA_DISABLED", "true")
+        .arg("--not-ec2")
+        .arg("--config")
+        .arg(&config_file)
+        .arg("--verify=false")
+        .arg("--report")
+        .arg("json")
+        .arg("s3://a-bucket/a-key")
+        .arg(home.path().join("destfile.txt"));
+
+    let output = cmd.output()?;
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+    assert_eq!(report["config"]["verify"], false);
+    assert_eq!(report["config"]["tcp_nodelay"], true);
+
+    Ok(())
+}